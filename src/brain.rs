@@ -1,15 +1,30 @@
+mod chain;
 mod chains_pack;
+pub(crate) mod filter;
+mod keywords;
+mod known_index;
+mod scoring;
+pub(crate) mod settings;
+pub(crate) mod stats;
+mod tokenizer;
 pub(crate) mod types;
 
-use chains_pack::Chains;
-use rand::{self, seq::SliceRandom};
-use redis::{aio::Connection, AsyncCommands};
-use telegram_bot::ChatId;
+use chains_pack::{Bounds, Chains};
+use filter::ContentFilter;
+use rand::{seq::SliceRandom, Rng};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use scoring::{Qualities, Weights};
+use settings::{ChatSettings, ForwardPolicy};
+use stats::{ChatStats, UserStats};
+use telegram_bot::{ChatId, MessageId};
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
 
-use super::CONFIG;
+use super::metrics::{self, METRICS};
+use super::{shutdown, CONFIG};
 
 #[allow(clippy::derive_hash_xor_eq)]
 #[derive(Eq, Hash, Clone, Debug)]
@@ -33,15 +48,56 @@ impl PartialEq for UserName {
     }
 }
 
+/// Generated reply, either a text or a sticker
+#[derive(Clone, Debug)]
+pub(crate) enum Reply {
+    Text(String),
+    Sticker {
+        file_id: String,
+        emoji: Option<String>,
+    },
+}
+
+/// How far learning chat history went
+pub(crate) struct Learned {
+    pub(crate) messages: usize,
+    // shutdown stopped learning before the history was over
+    pub(crate) interrupted: bool,
+}
+
+// generated text along with its author and how good it is
+struct Candidate {
+    name: UserName,
+    tokens: Vec<String>,
+    score: f64,
+}
+
+// message which is not learned yet as it still may be edited
+struct PendingMessage {
+    id: MessageId,
+    name: UserName,
+    text: String,
+    received: Instant,
+}
+
+// how many history messages to learn before giving other tasks a chance to run
+const LEARN_BATCH_SIZE: usize = 1000;
+// how many texts to generate to pick the best reply of
+const CANDIDATES: usize = 5;
+
 pub(crate) struct Brain {
     min_order: usize,
     max_order: usize,
-    msg_fed: usize,
 
     users: HashMap<ChatId, HashMap<UserName, Chains>>,
+    settings: HashMap<ChatId, ChatSettings>,
+    // quiz points per player
+    leaderboards: HashMap<ChatId, HashMap<String, u64>>,
+    pending: HashMap<ChatId, VecDeque<PendingMessage>>,
     loaded: HashSet<ChatId>,
+    last_used: HashMap<ChatId, Instant>,
 
-    redis_con: Option<Connection>,
+    redis_con: Option<ConnectionManager>,
 }
 
 impl Brain {
@@ -49,20 +105,32 @@ impl Brain {
         Brain {
             min_order,
             max_order,
-            msg_fed: 0,
 
             users: HashMap::new(),
+            settings: HashMap::new(),
+            leaderboards: HashMap::new(),
+            pending: HashMap::new(),
             loaded: HashSet::new(),
+            last_used: HashMap::new(),
 
             redis_con: None,
         }
     }
 
-    pub(crate) fn set_redis_con(mut self, redis_con: Connection) -> Self {
+    pub(crate) fn set_redis_con(mut self, redis_con: ConnectionManager) -> Self {
         self.redis_con = Some(redis_con);
         self
     }
 
+    /// Pings Redis, if the connection is dropped it is restored in background
+    pub(crate) async fn check_redis(&mut self) {
+        if let Some(ref mut redis_con) = self.redis_con {
+            if let Err(err) = redis::cmd("PING").query_async::<_, ()>(redis_con).await {
+                log::error!("Redis health check failed: {}", err);
+            }
+        }
+    }
+
     /// Returns true if we already have some data for the given user
     /// and false otherwise
     pub(crate) fn is_known_user(&self, chat_id: ChatId, user_name: &UserName) -> bool {
@@ -78,25 +146,34 @@ impl Brain {
         format!("{}_{}", chat_id, user_name.0)
     }
 
+    fn settings_redis_key(&self, chat_id: ChatId) -> String {
+        format!("settings_{}", chat_id)
+    }
+
+    fn leaderboard_redis_key(&self, chat_id: ChatId) -> String {
+        format!("leaderboard_{}", chat_id)
+    }
+
     /// Writes new data per person to Redis
     async fn write_to_redis(&mut self, chat_id: ChatId, user_name: UserName) -> anyhow::Result<()> {
         if !self.is_known_user(chat_id, &user_name) {
             return Ok(());
         }
 
-        let chains = &self
+        let key = self.redis_key(chat_id, user_name.clone());
+
+        let chains = self
             .users
-            .get(&chat_id)
+            .get_mut(&chat_id)
             .expect("chat id data must exist on this step")
-            .get(&user_name)
+            .get_mut(&user_name)
             .unwrap();
 
-        let key = self.redis_key(chat_id, user_name);
-
         match self.redis_con {
             Some(ref mut redis_con) => {
                 let raw = chains.serialize()?;
-                redis_con.set(key, raw).await?;
+                metrics::timed("write", redis_con.set::<_, _, ()>(key, raw)).await?;
+                chains.mark_saved();
             }
             None => {
                 log::warn!("write_to_redis: can't save learn data, redis client is not set");
@@ -106,8 +183,54 @@ impl Brain {
         Ok(())
     }
 
+    /// Learns messages no longer expected to be edited and writes all chains
+    /// having unsaved changes to Redis
+    pub(crate) async fn flush(&mut self) {
+        let edit_timeout = Duration::from_secs(CONFIG.get().edit_timeout_sec);
+        self.learn_expired(Instant::now(), edit_timeout).await;
+
+        let dirty = self
+            .users
+            .iter()
+            .flat_map(|(chat_id, users)| {
+                users
+                    .iter()
+                    .filter(|(_, chains)| chains.unsaved() > 0)
+                    .map(move |(name, _)| (*chat_id, name.clone()))
+            })
+            .collect::<Vec<(ChatId, UserName)>>();
+
+        if dirty.is_empty() {
+            return;
+        }
+
+        log::info!("saving data for {} users...", dirty.len());
+
+        for (chat_id, name) in dirty {
+            if let Err(err) = self.write_to_redis(chat_id, name.clone()).await {
+                log::error!(
+                    "error writing data for {} in chat {} to redis: {}",
+                    name,
+                    chat_id,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Learns all messages still waiting for edits and saves everything to Redis,
+    /// to be called before the bot exits
+    pub(crate) async fn flush_all(&mut self) {
+        let chats = self.pending.keys().cloned().collect::<Vec<ChatId>>();
+        for chat_id in chats {
+            self.learn_pending(chat_id).await;
+        }
+
+        self.flush().await;
+    }
+
     /// Reads all data from redis for required chat
-    pub(crate) async fn read_from_redis(&mut self, chat_id: ChatId) -> anyhow::Result<()> {
+    async fn read_from_redis(&mut self, chat_id: ChatId) -> anyhow::Result<()> {
         // check whether we have data for this chat already loaded into memory
         if self.loaded.contains(&chat_id) {
             return Ok(());
@@ -116,28 +239,50 @@ impl Brain {
         log::info!("preparing to load data for chat id {}", chat_id);
 
         let mut user_data: HashMap<UserName, String> = HashMap::new();
+        let mut settings_data: Option<String> = None;
+        let mut leaderboard: HashMap<String, u64> = HashMap::new();
+        let settings_key = self.settings_redis_key(chat_id);
+        let leaderboard_key = self.leaderboard_redis_key(chat_id);
 
         match self.redis_con {
             Some(ref mut redis_con) => {
-                let key_patt = format!("{}*", i64::from(chat_id));
-                let keys: Vec<String> = redis_con.keys(key_patt).await?;
+                let key_patt = format!("{}_*", i64::from(chat_id));
+                let keys: Vec<String> = metrics::timed("read", redis_con.keys(key_patt)).await?;
 
                 for key in keys {
                     let (_, name) = key.split_once('_').unwrap();
-                    let raw = redis_con.get(&key).await?;
+                    let raw = metrics::timed("read", redis_con.get(&key)).await?;
                     user_data.insert(UserName::from(name), raw);
                 }
+
+                settings_data = metrics::timed("read", redis_con.get(settings_key)).await?;
+                leaderboard = metrics::timed("read", redis_con.hgetall(leaderboard_key)).await?;
             }
             None => {
                 log::warn!("read_from_redis: can't read data for chat, redis client is not ready");
             }
         };
 
-        // deserialize
+        // deserialize, users are added once all of them are loaded,
+        // so broken data of one of them doesn't leave the chat half loaded
+        let mut users = HashMap::new();
         for (name, raw) in user_data {
             log::info!("loading data for {}...", name);
-            let chains = self.insert_new_chat_id_user(chat_id, &name);
-            chains.deserialize(&raw);
+            let mut chains = Chains::new(self.min_order, self.max_order);
+            chains
+                .deserialize(&raw)
+                .map_err(|err| anyhow::anyhow!("broken data of {}: {}", name, err))?;
+            users.insert(name, chains);
+        }
+        self.users.entry(chat_id).or_default().extend(users);
+
+        if let Some(raw) = settings_data {
+            self.settings
+                .insert(chat_id, ChatSettings::deserialize(&raw)?);
+        }
+
+        if !leaderboard.is_empty() {
+            self.leaderboards.insert(chat_id, leaderboard);
         }
 
         self.loaded.insert(chat_id);
@@ -146,6 +291,220 @@ impl Brain {
         Ok(())
     }
 
+    /// Makes sure data for the chat is in memory, unloading chats which
+    /// weren't used for a long time or don't fit into the memory budget
+    pub(crate) async fn load_chat(&mut self, chat_id: ChatId) -> anyhow::Result<()> {
+        self.last_used.insert(chat_id, Instant::now());
+        self.read_from_redis(chat_id).await?;
+        self.evict_chats(chat_id).await;
+        METRICS.chat_loaded(chat_id, self.chat_size(chat_id));
+        Ok(())
+    }
+
+    fn chat_size(&self, chat_id: ChatId) -> usize {
+        self.users
+            .get(&chat_id)
+            .map(|users| users.values().map(|chains| chains.approx_size()).sum())
+            .unwrap_or(0)
+    }
+
+    fn memory_used(&self) -> usize {
+        self.users
+            .keys()
+            .map(|chat_id| self.chat_size(*chat_id))
+            .sum()
+    }
+
+    async fn evict_chats(&mut self, current: ChatId) {
+        let budget = CONFIG.get().memory_budget_mb * 1024 * 1024;
+        let idle_timeout = Duration::from_secs(CONFIG.get().chat_idle_timeout_sec);
+
+        let mut chats = self
+            .last_used
+            .iter()
+            .filter(|(chat_id, _)| **chat_id != current)
+            .map(|(chat_id, used)| (*chat_id, *used))
+            .collect::<Vec<(ChatId, Instant)>>();
+
+        // least recently used go first
+        chats.sort_by_key(|(_, used)| *used);
+
+        let mut total = self.memory_used();
+
+        for (chat_id, used) in chats {
+            if used.elapsed() < idle_timeout && total <= budget {
+                break;
+            }
+
+            let size = self.chat_size(chat_id);
+
+            match self.unload_chat(chat_id).await {
+                Ok(()) => total = total.saturating_sub(size),
+                Err(err) => log::error!("error unloading chat {}: {}", chat_id, err),
+            }
+        }
+    }
+
+    /// Saves everything learned in the chat to Redis and drops it from memory,
+    /// it will be loaded again with the next message from the chat
+    async fn unload_chat(&mut self, chat_id: ChatId) -> anyhow::Result<()> {
+        if self.redis_con.is_none() {
+            anyhow::bail!("redis client is not set, chat data would be lost");
+        }
+
+        self.learn_pending(chat_id).await;
+
+        let names = self
+            .users
+            .get(&chat_id)
+            .map(|users| users.keys().cloned().collect::<Vec<UserName>>())
+            .unwrap_or_default();
+
+        for name in names {
+            self.write_to_redis(chat_id, name).await?;
+        }
+
+        self.users.remove(&chat_id);
+        self.settings.remove(&chat_id);
+        self.leaderboards.remove(&chat_id);
+        self.loaded.remove(&chat_id);
+        self.last_used.remove(&chat_id);
+        METRICS.chat_unloaded(chat_id);
+
+        log::info!("data for chat {} unloaded", chat_id);
+
+        Ok(())
+    }
+
+    /// Returns settings of the chat, default ones if they were never changed
+    pub(crate) fn settings(&self, chat_id: ChatId) -> ChatSettings {
+        self.settings.get(&chat_id).cloned().unwrap_or_default()
+    }
+
+    fn content_filter(&self, chat_id: ChatId) -> ContentFilter {
+        ContentFilter::new(&self.settings(chat_id).blocked)
+    }
+
+    async fn write_settings(&mut self, chat_id: ChatId) -> anyhow::Result<()> {
+        let raw = self.settings(chat_id).serialize()?;
+        let key = self.settings_redis_key(chat_id);
+
+        match self.redis_con {
+            Some(ref mut redis_con) => {
+                metrics::timed("write_settings", redis_con.set::<_, _, ()>(key, raw)).await?;
+            }
+            None => {
+                log::warn!("write_settings: can't save chat settings, redis client is not set");
+            }
+        };
+
+        Ok(())
+    }
+
+    pub(crate) async fn set_forward_policy(
+        &mut self,
+        chat_id: ChatId,
+        policy: ForwardPolicy,
+    ) -> anyhow::Result<()> {
+        self.settings.entry(chat_id).or_default().forwards = policy;
+        self.write_settings(chat_id).await
+    }
+
+    /// Gives a quiz point to each player
+    pub(crate) async fn add_points(
+        &mut self,
+        chat_id: ChatId,
+        players: &[UserName],
+    ) -> anyhow::Result<()> {
+        let key = self.leaderboard_redis_key(chat_id);
+        let leaderboard = self.leaderboards.entry(chat_id).or_default();
+
+        for player in players {
+            *leaderboard.entry(player.0.clone()).or_insert(0) += 1;
+
+            match self.redis_con {
+                Some(ref mut redis_con) => {
+                    let incr = redis_con.hincr::<_, _, _, ()>(&key, &player.0, 1);
+                    metrics::timed("write_leaderboard", incr).await?;
+                }
+                None => {
+                    log::warn!("add_points: can't save leaderboard, redis client is not set");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns quiz players with their points, the best go first
+    pub(crate) fn leaderboard(&self, chat_id: ChatId) -> Vec<(String, u64)> {
+        let mut res = self
+            .leaderboards
+            .get(&chat_id)
+            .map(|players| {
+                players
+                    .iter()
+                    .map(|(name, points)| (name.clone(), *points))
+                    .collect::<Vec<(String, u64)>>()
+            })
+            .unwrap_or_default();
+
+        res.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        res
+    }
+
+    /// Returns users the bot has learned in the chat
+    pub(crate) fn users(&self, chat_id: ChatId) -> Vec<UserName> {
+        self.users
+            .get(&chat_id)
+            .map(|users| users.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) async fn set_overlap(
+        &mut self,
+        chat_id: ChatId,
+        overlap: usize,
+    ) -> anyhow::Result<()> {
+        self.settings.entry(chat_id).or_default().overlap = overlap;
+        self.write_settings(chat_id).await
+    }
+
+    pub(crate) async fn set_weights(
+        &mut self,
+        chat_id: ChatId,
+        weights: Weights,
+    ) -> anyhow::Result<()> {
+        self.settings.entry(chat_id).or_default().weights = weights;
+        self.write_settings(chat_id).await
+    }
+
+    /// Adds entry checked by `filter::parse_entry` to the chat blocklist,
+    /// returns false if it is blocked already
+    pub(crate) async fn block(&mut self, chat_id: ChatId, entry: String) -> anyhow::Result<bool> {
+        let blocked = &mut self.settings.entry(chat_id).or_default().blocked;
+        if blocked.contains(&entry) {
+            return Ok(false);
+        }
+
+        blocked.push(entry);
+        self.write_settings(chat_id).await?;
+        Ok(true)
+    }
+
+    /// Removes entry from the chat blocklist, returns false if it wasn't there
+    pub(crate) async fn unblock(&mut self, chat_id: ChatId, entry: &str) -> anyhow::Result<bool> {
+        let blocked = &mut self.settings.entry(chat_id).or_default().blocked;
+        let len = blocked.len();
+        blocked.retain(|blocked| blocked != entry);
+        if blocked.len() == len {
+            return Ok(false);
+        }
+
+        self.write_settings(chat_id).await?;
+        Ok(true)
+    }
+
     fn insert_new_chat_id_user(&mut self, chat_id: ChatId, name: &UserName) -> &mut Chains {
         let min_order = self.min_order;
         let max_order = self.max_order;
@@ -157,19 +516,126 @@ impl Brain {
             .or_insert_with(|| Chains::new(min_order, max_order))
     }
 
-    pub(crate) async fn feed_message(
+    // Learns the message unless it has something blocked in the chat,
+    // the filter is built by callers as they often feed many messages at once
+    async fn feed_message(
         &mut self,
         chat_id: ChatId,
         name: UserName,
         msg: &str,
+        filter: &ContentFilter,
         write_to_redis: bool,
     ) {
+        if filter.blocks(msg) {
+            log::debug!(
+                "message with blocked content isn't learned in chat {}",
+                chat_id
+            );
+            return;
+        }
+
         let chains = self.insert_new_chat_id_user(chat_id, &name);
         chains.feed(msg);
 
-        self.msg_fed += 1;
+        self.after_feed(chat_id, name, write_to_redis).await;
+    }
+
+    /// Feeds message just sent to the chat, the latest messages are kept aside
+    /// for a while, so their edits could replace them before they are learned
+    pub(crate) async fn feed_live_message(
+        &mut self,
+        chat_id: ChatId,
+        id: MessageId,
+        name: UserName,
+        msg: &str,
+    ) {
+        let pending = self.pending.entry(chat_id).or_default();
+
+        pending.push_back(PendingMessage {
+            id,
+            name,
+            text: msg.to_owned(),
+            received: Instant::now(),
+        });
+
+        let overflow = pending.len().saturating_sub(CONFIG.get().edit_window);
+        let ready = pending.drain(..overflow).collect::<Vec<PendingMessage>>();
 
-        if write_to_redis && (self.msg_fed % CONFIG.write_to_redis_freq) == 0 {
+        let filter = self.content_filter(chat_id);
+        for msg in ready {
+            self.feed_message(chat_id, msg.name, &msg.text, &filter, true)
+                .await;
+        }
+    }
+
+    // Learns messages which have been waiting for edits for longer than `timeout`,
+    // otherwise the last messages of a quiet chat would wait until it's unloaded
+    async fn learn_expired(&mut self, now: Instant, timeout: Duration) {
+        let mut ready = Vec::new();
+
+        for (chat_id, pending) in &mut self.pending {
+            // messages are queued in the order they came in
+            let expired = pending
+                .iter()
+                .take_while(|msg| now.saturating_duration_since(msg.received) >= timeout)
+                .count();
+            ready.extend(pending.drain(..expired).map(|msg| (*chat_id, msg)));
+        }
+        self.pending.retain(|_, pending| !pending.is_empty());
+
+        for (chat_id, msg) in ready {
+            let filter = self.content_filter(chat_id);
+            self.feed_message(chat_id, msg.name, &msg.text, &filter, false)
+                .await;
+        }
+    }
+
+    // Learns messages of the chat waiting for edits, it's too late to edit them
+    async fn learn_pending(&mut self, chat_id: ChatId) {
+        if let Some(pending) = self.pending.remove(&chat_id) {
+            let filter = self.content_filter(chat_id);
+            for msg in pending {
+                self.feed_message(chat_id, msg.name, &msg.text, &filter, false)
+                    .await;
+            }
+        }
+    }
+
+    /// Replaces text of a message which is not learned yet,
+    /// returns false if the message is learned already or not known at all
+    pub(crate) fn edit_message(&mut self, chat_id: ChatId, id: MessageId, msg: &str) -> bool {
+        let pending = match self.pending.get_mut(&chat_id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        match pending.iter_mut().find(|pending_msg| pending_msg.id == id) {
+            Some(pending_msg) => {
+                pending_msg.text = msg.to_owned();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) async fn feed_sticker(
+        &mut self,
+        chat_id: ChatId,
+        name: UserName,
+        file_id: &str,
+        emoji: Option<&str>,
+    ) {
+        let chains = self.insert_new_chat_id_user(chat_id, &name);
+        chains.feed_sticker(file_id, emoji);
+
+        self.after_feed(chat_id, name, true).await;
+    }
+
+    async fn after_feed(&mut self, chat_id: ChatId, name: UserName, write_to_redis: bool) {
+        let unsaved = self.users[&chat_id][&name].unsaved();
+
+        // the rest is saved by periodic flush
+        if write_to_redis && unsaved >= CONFIG.get().write_to_redis_freq {
             if let Err(err) = self.write_to_redis(chat_id, name).await {
                 log::error!("error writing new data to redis: {}", err);
             }
@@ -181,11 +647,29 @@ impl Brain {
         chat_id: ChatId,
         input: types::Source,
         req_name: Option<UserName>,
-    ) -> anyhow::Result<usize> {
+    ) -> anyhow::Result<Learned> {
         let mut proccessed = 0;
+        let mut interrupted = false;
         let mut names = HashSet::new();
+        let filter = self.content_filter(chat_id);
+
+        for (i, item) in input.messages.into_iter().enumerate() {
+            if i % LEARN_BATCH_SIZE == 0 {
+                #[allow(unused_must_use)]
+                tokio::task::yield_now().await;
+
+                if shutdown::requested() {
+                    // save what is learned so far
+                    log::warn!(
+                        "learning for chat {} interrupted by shutdown after {} messages",
+                        chat_id,
+                        proccessed
+                    );
+                    interrupted = true;
+                    break;
+                }
+            }
 
-        for item in input.messages {
             if let Some(name) = item.from {
                 if let Some(ref tmp) = req_name {
                     if *tmp != UserName(name.clone()) {
@@ -197,7 +681,7 @@ impl Brain {
 
                 if let types::Text::Text { ref text } = item.text {
                     if !text.is_empty() {
-                        self.feed_message(chat_id, UserName(name), text, false)
+                        self.feed_message(chat_id, UserName(name), text, &filter, false)
                             .await;
                         proccessed += 1;
                     }
@@ -215,84 +699,368 @@ impl Brain {
             }
         }
 
-        Ok(proccessed)
+        Ok(Learned {
+            messages: proccessed,
+            interrupted,
+        })
     }
 
-    fn choose_user(&self, chat_id: ChatId) -> Option<UserName> {
-        let users = self.users.get(&chat_id);
-        let users_list = match users {
-            Some(users) => users.keys().collect::<Vec<&UserName>>(),
-            None => return None,
+    /// Returns what is known about the chat, or about a single user of it if the name is given
+    pub(crate) fn stats(&self, chat_id: ChatId, only: Option<&UserName>) -> ChatStats {
+        let empty = HashMap::new();
+        let users = self.users.get(&chat_id).unwrap_or(&empty);
+
+        let mut chat_counts = HashMap::new();
+        for chains in users.values() {
+            for (token, count) in chains.token_counts() {
+                *chat_counts.entry(token.clone()).or_insert(0) += count;
+            }
+        }
+
+        let mut res = Vec::new();
+        for (name, chains) in users {
+            if matches!(only, Some(only) if only != name) {
+                continue;
+            }
+
+            let distinctive = stats::distinctive_words(chains.token_counts(), &chat_counts);
+            res.push(UserStats {
+                name: name.clone(),
+                counts: chains.stats(),
+                distinctive: chains.restore_casing(&distinctive),
+            });
+        }
+
+        res.sort_by_key(|user| Reverse(user.counts.messages));
+        ChatStats { users: res }
+    }
+
+    /// Scores the text against chains of every user in the chat, returns the most likely
+    /// authors with probabilities of them being the author
+    pub(crate) fn whosaid(&self, chat_id: ChatId, text: &str, top: usize) -> Vec<(UserName, f64)> {
+        let tokens = Chains::tokenize(text);
+        let users = match self.users.get(&chat_id) {
+            Some(users) if !tokens.is_empty() => users,
+            _ => return Vec::new(),
         };
 
-        if users_list.is_empty() {
-            return None;
+        let vocabulary = Self::vocabulary(users);
+
+        let mut scores = users
+            .iter()
+            .map(|(name, chains)| (name.clone(), chains.log_likelihood(&tokens, vocabulary)))
+            // broken data may give scores which can't be compared with others, leave them out
+            .filter(|(_, score)| score.is_finite())
+            .collect::<Vec<(UserName, f64)>>();
+
+        // turn log likelihoods into probabilities, every user is equally likely beforehand
+        let max = scores
+            .iter()
+            .map(|(_, score)| *score)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let total = scores
+            .iter()
+            .map(|(_, score)| (score - max).exp())
+            .sum::<f64>();
+        for (_, score) in &mut scores {
+            *score = (*score - max).exp() / total;
         }
 
-        let mut rng = rand::thread_rng();
-        let choice = users_list.choose(&mut rng).unwrap();
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0 .0.cmp(&b.0 .0)));
+        scores.truncate(top);
+        scores
+    }
+
+    // number of distinct tokens the users know
+    fn vocabulary(users: &HashMap<UserName, Chains>) -> usize {
+        users
+            .values()
+            .flat_map(|chains| chains.token_counts().keys())
+            .collect::<HashSet<&String>>()
+            .len()
+    }
+
+    fn choose_user(&self, chat_id: ChatId, rng: &mut impl Rng) -> Option<UserName> {
+        let mut users_list = match self.users.get(&chat_id) {
+            Some(users) => users.keys().collect::<Vec<&UserName>>(),
+            None => return None,
+        };
 
-        Some((*choice).clone())
+        // hash map order differs from run to run, the same rng must pick the same user
+        users_list.sort_by(|a, b| a.0.cmp(&b.0));
+        users_list.choose(rng).map(|choice| (*choice).clone())
     }
 
     /// Converts the output of `generate(...)` on a String chain to a single String.
-    fn vec_to_string(&self, vec: &[String]) -> String {
-        let mut ret = String::new();
-        for s in vec {
-            ret.push_str(&s);
-            ret.push_str(" ");
-        }
-        let len = ret.len();
-        if len > 0 {
-            ret.truncate(len - 1);
+    fn vec_to_string(&self, chains: &Chains, vec: &[String]) -> String {
+        tokenizer::detokenize(&chains.restore_casing(vec))
+    }
+
+    /// Makes reply from generated tokens, stickers are always fed alone,
+    /// so a sticker token can only be generated alone too
+    fn make_reply(&self, chains: &Chains, tokens: &[String]) -> Reply {
+        if let [token] = tokens {
+            if let Some(file_id) = tokenizer::sticker_file_id(token) {
+                return Reply::Sticker {
+                    file_id: file_id.to_owned(),
+                    emoji: chains.sticker_emoji(file_id).map(|emoji| emoji.to_owned()),
+                };
+            }
         }
-        ret
+
+        Reply::Text(self.vec_to_string(chains, tokens))
     }
 
-    pub(crate) fn gen_from_token(
+    // Generates up to `limit` texts with chains of random users of the chat and rates them,
+    // `seeds` are words the texts should preferably contain
+    fn candidates<R: Rng>(
         &self,
         chat_id: ChatId,
-        token: &str,
-        order: usize,
-    ) -> Option<(UserName, String)> {
-        for _ in 0..CONFIG.max_gen_retries {
-            let name = match self.choose_user(chat_id) {
+        seeds: &[String],
+        limit: usize,
+        rng: &mut R,
+        gen: impl Fn(&Chains, &mut R) -> Option<Vec<String>>,
+    ) -> Vec<Candidate> {
+        let users = match self.users.get(&chat_id) {
+            Some(users) => users,
+            None => return Vec::new(),
+        };
+        let settings = self.settings(chat_id);
+        let filter = self.content_filter(chat_id);
+        let vocabulary = Self::vocabulary(users);
+
+        let mut res = Vec::new();
+
+        for _ in 0..CONFIG.get().max_gen_retries {
+            if res.len() >= limit {
+                break;
+            }
+
+            let name = match self.choose_user(chat_id, rng) {
                 Some(name) => name,
-                None => return None,
+                None => break,
             };
 
-            let chains = &self.users[&chat_id][&name];
+            let chains = &users[&name];
 
-            if let Some(tokens) = chains.gen_from_token(token).get(&order) {
-                if tokens.len() < CONFIG.max_reply_tokens {
-                    return Some((name.clone(), self.vec_to_string(tokens)));
+            let tokens = match gen(chains, rng) {
+                Some(tokens) => tokens,
+                None => continue,
+            };
+
+            if let Reply::Text(text) = self.make_reply(chains, &tokens) {
+                if !filter.allows(&text) {
+                    log::debug!("generated text is filtered out in chat {}", chat_id);
+                    continue;
                 }
             }
+
+            let score = Qualities::of(&tokens, seeds, chains, vocabulary).score(&settings.weights);
+            res.push(Candidate {
+                name,
+                tokens,
+                score,
+            });
         }
 
-        None
+        res
     }
 
-    pub(crate) fn gen_from_empty(
+    // Makes reply of the best candidate
+    fn best_reply(&self, chat_id: ChatId, candidates: Vec<Candidate>) -> Option<(UserName, Reply)> {
+        metrics::inc(&METRICS.generations);
+
+        // the first of equally good candidates wins, so the same rng gives the same reply
+        let best =
+            candidates
+                .into_iter()
+                .fold(None, |best: Option<Candidate>, candidate| match best {
+                    Some(best) if best.score >= candidate.score => Some(best),
+                    _ => Some(candidate),
+                });
+
+        match best {
+            Some(best) => {
+                let chains = &self.users[&chat_id][&best.name];
+                let reply = self.make_reply(chains, &best.tokens);
+                Some((best.name, reply))
+            }
+            None => {
+                metrics::inc(&METRICS.generation_failures);
+                None
+            }
+        }
+    }
+
+    fn bounds() -> Bounds {
+        Bounds {
+            min: CONFIG.get().min_reply_tokens,
+            max: CONFIG.get().max_reply_tokens,
+        }
+    }
+
+    fn has_users(&self, chat_id: ChatId) -> bool {
+        matches!(self.users.get(&chat_id), Some(users) if !users.is_empty())
+    }
+
+    /// Picks words from the message to start a reply with, see `keywords::seeds`
+    fn extract_seeds(&self, chat_id: ChatId, msg: &str) -> Vec<String> {
+        let chat_users = match self.users.get(&chat_id) {
+            Some(chat_users) => chat_users,
+            None => return Vec::new(),
+        };
+
+        keywords::seeds(
+            &Chains::tokenize(msg),
+            |word| {
+                chat_users
+                    .values()
+                    .map(|chains| chains.token_count(word))
+                    .sum()
+            },
+            CONFIG.get().max_seed_candidates,
+        )
+    }
+
+    /// Generates reply to the message trying several of its words as a seed,
+    /// the best of replies generated from all of them is taken
+    pub(crate) fn gen_from_message(
         &self,
         chat_id: ChatId,
+        msg: &str,
         order: usize,
-    ) -> Option<(UserName, String)> {
-        for _ in 0..CONFIG.max_gen_retries {
-            let name = match self.choose_user(chat_id) {
-                Some(name) => name,
-                None => return None,
-            };
+        rng: &mut impl Rng,
+    ) -> Option<(UserName, Reply)> {
+        let seeds = self.extract_seeds(chat_id, msg);
+        if seeds.is_empty() || !self.has_users(chat_id) {
+            return None;
+        }
 
-            let chains = &self.users[&chat_id][&name];
+        let overlap = self.settings(chat_id).overlap;
+        let bounds = Self::bounds();
+        let mut candidates = Vec::new();
 
-            if let Some(tokens) = chains.gen_from_empty().get(&order) {
-                if tokens.len() < CONFIG.max_reply_tokens {
-                    return Some((name.clone(), self.vec_to_string(tokens)));
-                }
+        // remaining candidates are shared evenly between the seeds left,
+        // so the first seed doesn't take all of them
+        for (i, seed) in seeds.iter().enumerate() {
+            let remaining = CANDIDATES - candidates.len();
+            if remaining == 0 {
+                break;
             }
+
+            let left = seeds.len() - i;
+            let limit = remaining.div_ceil(left);
+
+            candidates.extend(self.candidates(chat_id, &seeds, limit, rng, |chains, rng| {
+                chains.gen_from_token(seed, order, overlap, bounds, rng)
+            }));
+        }
+
+        self.best_reply(chat_id, candidates)
+    }
+
+    pub(crate) fn gen_from_empty(
+        &self,
+        chat_id: ChatId,
+        order: usize,
+        rng: &mut impl Rng,
+    ) -> Option<(UserName, Reply)> {
+        if !self.has_users(chat_id) {
+            return None;
+        }
+
+        let overlap = self.settings(chat_id).overlap;
+        let bounds = Self::bounds();
+        let candidates = self.candidates(chat_id, &[], CANDIDATES, rng, |chains, rng| {
+            chains.gen_from_empty(order, overlap, bounds, rng)
+        });
+
+        self.best_reply(chat_id, candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pending_messages_are_learned_after_timeout() {
+        let mut brain = Brain::new(1, 2);
+        let chat_id = ChatId::new(1);
+        let name = UserName::from("Alice");
+        let start = Instant::now();
+
+        for (id, text) in ["hello there", "general kenobi"].iter().enumerate() {
+            brain
+                .pending
+                .entry(chat_id)
+                .or_default()
+                .push_back(PendingMessage {
+                    id: MessageId::new(id as i64),
+                    name: name.clone(),
+                    text: text.to_string(),
+                    received: start + Duration::from_secs(id as u64 * 10),
+                });
         }
+        let timeout = Duration::from_secs(60);
+
+        brain
+            .learn_expired(start + Duration::from_secs(30), timeout)
+            .await;
+        assert!(!brain.is_known_user(chat_id, &name));
+
+        brain
+            .learn_expired(start + Duration::from_secs(65), timeout)
+            .await;
+        assert_eq!(brain.users[&chat_id][&name].token_count("hello"), 1);
+        assert_eq!(brain.users[&chat_id][&name].token_count("kenobi"), 0);
+        assert!(brain.edit_message(chat_id, MessageId::new(1), "general grievous"));
+        assert!(!brain.edit_message(chat_id, MessageId::new(0), "hi there"));
+
+        brain
+            .learn_expired(start + Duration::from_secs(75), timeout)
+            .await;
+        assert_eq!(brain.users[&chat_id][&name].token_count("grievous"), 1);
+        assert!(brain.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocked_messages_are_not_learned() {
+        let mut brain = Brain::new(1, 2);
+        let chat_id = ChatId::new(1);
+        let name = UserName::from("Alice");
+        brain.block(chat_id, "darn".to_owned()).await.unwrap();
+
+        let history = serde_json::from_str::<types::Source>(
+            r#"{"messages": [
+                {"id": 1, "type": "message", "date": "2020-11-01T10:00:00", "from": "Alice", "text": "Darn it, not again"},
+                {"id": 2, "type": "message", "date": "2020-11-01T10:01:00", "from": "Alice", "text": "hello there"}
+            ]}"#,
+        )
+        .unwrap();
+        brain.learn_from_hist(chat_id, history, None).await.unwrap();
+
+        let chains = &brain.users[&chat_id][&name];
+        assert_eq!(chains.token_count("hello"), 1);
+        assert_eq!(chains.token_count("darn"), 0);
+        assert_eq!(chains.token_count("again"), 0);
+    }
+
+    #[test]
+    fn whosaid_ranks_users_knowing_nothing_last() {
+        let mut brain = Brain::new(1, 2);
+        let chat_id = ChatId::new(1);
+
+        let mut alice = Chains::new(1, 2);
+        alice.feed("hello there");
+        let users = brain.users.entry(chat_id).or_default();
+        users.insert(UserName::from("Alice"), alice);
+        users.insert(UserName::from("Bob"), Chains::new(1, 2));
 
-        None
+        let scores = brain.whosaid(chat_id, "hello there", 3);
+        assert_eq!(scores.len(), 2);
+        assert_eq!(scores[0].0, UserName::from("Alice"));
+        assert!(scores[0].1 > scores[1].1);
+        assert!(brain.whosaid(chat_id, "", 3).is_empty());
     }
 }
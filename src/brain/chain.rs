@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// weight of word frequencies mixed into transition probabilities when scoring texts,
+// lets transitions the user has never made still have some chance
+const SMOOTHING: f64 = 1.0;
+
+// last `order` tokens, None stands for the message boundary
+type State = Vec<Option<String>>;
+
+/// Markov chain over tokens, laid out the same way as markov::Chain used to be so saved
+/// chains load as is, next tokens are kept ordered so a seeded rng always picks the same ones
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Chain {
+    map: HashMap<State, BTreeMap<Option<String>, usize>>,
+    order: usize,
+}
+
+impl Chain {
+    pub(crate) fn of_order(order: usize) -> Self {
+        let mut map = HashMap::new();
+        map.insert(vec![None; order], BTreeMap::new());
+        Chain { map, order }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages() == 0
+    }
+
+    // message with enough boundaries before it to make the first state and one after it
+    fn pad(&self, tokens: &[String]) -> State {
+        vec![None; self.order]
+            .into_iter()
+            .chain(tokens.iter().cloned().map(Some))
+            .chain(Some(None))
+            .collect()
+    }
+
+    pub(crate) fn feed(&mut self, tokens: &[String]) {
+        if tokens.is_empty() {
+            return;
+        }
+
+        for window in self.pad(tokens).windows(self.order + 1) {
+            let (state, next) = window.split_at(self.order);
+            *self
+                .map
+                .entry(state.to_vec())
+                .or_default()
+                .entry(next[0].clone())
+                .or_insert(0) += 1;
+        }
+    }
+
+    // picks next token with probability proportional to how often it followed the state
+    fn next(&self, state: &[Option<String>], rng: &mut impl Rng) -> Option<String> {
+        let nexts = self.map.get(state)?;
+        let total = nexts.values().sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+
+        let mut cap = rng.gen_range(0, total);
+        for (token, count) in nexts {
+            if cap < *count {
+                return token.clone();
+            }
+            cap -= count;
+        }
+
+        None
+    }
+
+    fn walk(&self, mut state: State, limit: usize, rng: &mut impl Rng) -> Vec<String> {
+        let mut res = Vec::new();
+
+        while res.len() < limit {
+            let token = match self.next(&state, rng) {
+                Some(token) => token,
+                None => break,
+            };
+            state.remove(0);
+            state.push(Some(token.clone()));
+            res.push(token);
+        }
+
+        res
+    }
+
+    /// Generates a message from its start, generation stops after `limit` tokens
+    /// even if the message doesn't end there
+    pub(crate) fn generate(&self, limit: usize, rng: &mut impl Rng) -> Vec<String> {
+        self.walk(vec![None; self.order], limit, rng)
+    }
+
+    /// Finds where generation may continue from the token, wherever in a message it was
+    pub(crate) fn seeded(&self, token: &str) -> Seeded<'_> {
+        let mut states = self
+            .map
+            .iter()
+            .filter(|(state, _)| matches!(state.last(), Some(Some(last)) if last == token))
+            .map(|(state, nexts)| (state, nexts.values().sum::<usize>()))
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<(&State, usize)>>();
+        // map order differs between runs, a seeded rng must pick the same state
+        states.sort();
+
+        Seeded {
+            chain: self,
+            token: token.to_owned(),
+            states,
+        }
+    }
+
+    // each fed message starts from the state consisting of nothing but boundaries
+    pub(crate) fn messages(&self) -> usize {
+        self.map
+            .get(&vec![None; self.order])
+            .map(|nexts| nexts.values().sum())
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn transitions(&self) -> usize {
+        self.map.values().map(|nexts| nexts.len()).sum()
+    }
+
+    // every occurrence of a token ends exactly one state, so summing transitions
+    // out of such states gives the number of times the token was fed
+    pub(crate) fn token_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+
+        for (state, nexts) in &self.map {
+            if let Some(Some(token)) = state.last() {
+                *counts.entry(token.clone()).or_insert(0) += nexts.values().sum::<usize>();
+            }
+        }
+
+        counts
+    }
+
+    // probability of every transition in the message, including its start and end,
+    // is the transition frequency smoothed with the probability of the next token alone
+    pub(crate) fn log_likelihood(
+        &self,
+        tokens: &[String],
+        token_prob: impl Fn(Option<&String>) -> f64,
+    ) -> f64 {
+        self.pad(tokens)
+            .windows(self.order + 1)
+            .map(|window| {
+                let (state, next) = window.split_at(self.order);
+                let nexts = self.map.get(state);
+
+                let seen = nexts.and_then(|nexts| nexts.get(&next[0])).copied();
+                let total = nexts.map_or(0, |nexts| nexts.values().sum::<usize>());
+                let prior = token_prob(next[0].as_ref());
+
+                ((seen.unwrap_or(0) as f64 + SMOOTHING * prior) / (total as f64 + SMOOTHING)).ln()
+            })
+            .sum()
+    }
+}
+
+/// States of a chain ending with the token, generation starts from one of them
+pub(crate) struct Seeded<'a> {
+    chain: &'a Chain,
+    token: String,
+    // each state with the number of times it was followed by anything
+    states: Vec<(&'a State, usize)>,
+}
+
+impl Seeded<'_> {
+    /// Returns true if the token was never fed into the chain
+    pub(crate) fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Generates a message starting with the token, what precedes the token is picked
+    /// as often as it preceded the token in fed messages
+    pub(crate) fn generate(&self, limit: usize, rng: &mut impl Rng) -> Vec<String> {
+        let total = self.states.iter().map(|(_, count)| count).sum::<usize>();
+        if total == 0 {
+            return Vec::new();
+        }
+
+        let mut cap = rng.gen_range(0, total);
+        let state = self
+            .states
+            .iter()
+            .find(|(_, count)| {
+                let found = cap < *count;
+                cap = cap.saturating_sub(*count);
+                found
+            })
+            .map(|(state, _)| (*state).clone())
+            .unwrap_or_default();
+
+        let mut res = vec![self.token.clone()];
+        res.extend(self.chain.walk(state, limit.saturating_sub(1), rng));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split(' ').map(|token| token.to_owned()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_text() {
+        let mut chain = Chain::of_order(1);
+        for text in &["a b c", "a c b", "b a c a b", "c c a"] {
+            chain.feed(&tokens(text));
+        }
+
+        let generate = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .map(|_| chain.generate(100, &mut rng))
+                .collect::<Vec<Vec<String>>>()
+        };
+
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+
+    #[test]
+    fn generation_continues_from_any_position() {
+        let mut chain = Chain::of_order(2);
+        chain.feed(&tokens("a b c d"));
+        let mut rng = StdRng::seed_from_u64(1);
+
+        assert_eq!(chain.seeded("c").generate(10, &mut rng), tokens("c d"));
+        assert_eq!(chain.seeded("d").generate(10, &mut rng), tokens("d"));
+        assert!(chain.seeded("e").is_empty());
+    }
+
+    #[test]
+    fn loads_markov_layout() {
+        let raw = "---\nmap:\n  ? - ~\n  : hi: 2\n  ? - hi\n  : ~: 2\norder: 1\n";
+        let chain = serde_yaml::from_str::<Chain>(raw).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        assert_eq!(chain.messages(), 2);
+        assert_eq!(chain.generate(10, &mut rng), tokens("hi"));
+        assert_eq!(chain.seeded("hi").generate(10, &mut rng), tokens("hi"));
+        assert!(chain.seeded("bye").is_empty());
+        assert_eq!(chain.token_counts()["hi"], 2);
+    }
+}
@@ -2,143 +2,740 @@ use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 
-use markov::Chain;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_yaml::Result;
 
+use super::chain::{Chain, Seeded};
+use super::filter;
+use super::known_index::KnownIndex;
+use super::stats::Counts;
+use super::tokenizer;
+
 const MAX_GEN_RETRIES: usize = 1000;
 
+// rough memory cost of a single token occurrence in a chain, including hash map bookkeeping,
+// used to estimate memory taken by chains
+const TOKEN_OVERHEAD: usize = 48;
+
+/// Shortest and longest verbatim runs of training tokens the originality check can look for
+pub(crate) const MIN_OVERLAP: usize = 4;
+pub(crate) const MAX_OVERLAP: usize = 8;
+
+// mixed into the hash of a whole message, so it differs from hash of the same tokens
+// found inside a longer message
+const FULL_MESSAGE_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+const NGRAM_HASH_BASE: u64 = 0x0000_0100_0000_01b3;
+
+/// Bounds on the number of tokens in generated text
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bounds {
+    pub(crate) min: usize,
+    pub(crate) max: usize,
+}
+
+impl Bounds {
+    // Texts are generated with a limit of max + 1 tokens, so one not longer than max ended
+    // where a learned message ended. A longer one is cut after the last sentence end leaving
+    // at least min tokens, so it still ends naturally, returns None if there is no such
+    // sentence end or the text is too short, a sticker is fine alone though
+    fn fit(&self, mut tokens: Vec<String>) -> Option<Vec<String>> {
+        if let [token] = tokens.as_slice() {
+            if tokenizer::sticker_file_id(token).is_some() {
+                return Some(tokens);
+            }
+        }
+
+        if tokens.len() > self.max {
+            let len = (self.min.max(1)..=self.max)
+                .rev()
+                .find(|len| tokenizer::is_sentence_end(&tokens[len - 1]))?;
+            tokens.truncate(len);
+        }
+
+        if tokens.len() < self.min {
+            return None;
+        }
+
+        Some(tokens)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct Inner {
-    chains: HashMap<usize, Chain<String>>,
+    chains: HashMap<usize, Chain>,
 
-    // we only need to save hashes of string because we don't need to restore original messages
-    // just check if message exists in known_messages
+    // same chains trained on reversed messages, allow to generate text preceding a token
+    #[serde(default)]
+    backward: HashMap<usize, Chain>,
+
+    // hashes of every run of MIN_OVERLAP..=MAX_OVERLAP tokens and of every whole message learned,
+    // enough to tell whether generated text repeats the training verbatim
+    #[serde(default)]
+    known: KnownIndex,
+
+    // index also has hashes of all the token slices of messages learned before n-grams
+    // were introduced, generated text is checked against them the old way too
+    #[serde(default)]
+    legacy_known: bool,
+
+    // indexes of older versions, moved into `known` when loaded
+    #[serde(default, skip_serializing)]
+    known_ngrams: HashSet<u64>,
+    #[serde(default, skip_serializing)]
     known_messages: HashSet<u64>,
+
+    // how many times each token was seen, used to find rare (meaningful) words
+    #[serde(default)]
+    token_counts: HashMap<String, usize>,
+
+    // chains work with lowercased tokens, here we keep original spelling of words
+    // like names or abbreviations to restore it in generated text
+    #[serde(default)]
+    casing: HashMap<String, String>,
+
+    // stickers sent by the user, file id to emoji associated with it
+    #[serde(default)]
+    stickers: HashMap<String, String>,
 }
 
 impl Inner {
     fn new(from_ord: usize, to_ord: usize) -> Self {
         let mut chains = HashMap::new();
+        let mut backward = HashMap::new();
 
         for order in from_ord..=to_ord {
             chains.insert(order, Chain::of_order(order));
+            backward.insert(order, Chain::of_order(order));
         }
 
         Inner {
             chains,
+            backward,
+            known: KnownIndex::default(),
+            legacy_known: false,
+            known_ngrams: HashSet::new(),
             known_messages: HashSet::new(),
+            token_counts: HashMap::new(),
+            casing: HashMap::new(),
+            stickers: HashMap::new(),
         }
     }
 
-    fn get_hash(&self, tokens: &[String]) -> u64 {
+    fn legacy_hash(tokens: &[String]) -> u64 {
         let mut hasher = DefaultHasher::default();
         tokens.hash(&mut hasher);
         hasher.finish()
     }
 
     fn remember_known(&mut self, tokens: &[String]) {
-        let tmp = tokens
-            .iter()
-            .map(|t| t.to_lowercase())
-            .collect::<Vec<String>>();
-        for i in 0..tokens.len() {
-            for j in i..tokens.len() + 1 {
-                self.known_messages
-                    .insert(self.get_hash(&Vec::from(&tmp[i..j])));
+        let hashes = token_hashes(tokens);
+
+        self.known.insert(run_hash(&hashes) ^ FULL_MESSAGE_SALT);
+
+        for len in MIN_OVERLAP..=MAX_OVERLAP {
+            for run in hashes.windows(len) {
+                self.known.insert(run_hash(run));
             }
         }
     }
 
-    fn check_known(&self, generated: &[String]) -> bool {
-        let tmp = generated
-            .iter()
-            .map(|t| t.to_lowercase())
-            .collect::<Vec<String>>();
-        self.known_messages.contains(&self.get_hash(&tmp))
+    // moves hashes saved by older versions into the bounded index, hashes of all the token
+    // slices of a message grow quadratically with its length, so for heavy users they are
+    // dropped rather than crowding out everything else
+    fn migrate_known(&mut self) {
+        let mut old = self.known_ngrams.len() + self.known_messages.len();
+        if old > KnownIndex::capacity() && !self.known_messages.is_empty() {
+            log::warn!(
+                "{} hashes of old token slices don't fit into the index and are dropped",
+                self.known_messages.len()
+            );
+            self.known_messages.clear();
+            old = self.known_ngrams.len();
+        }
+        if old == 0 {
+            return;
+        }
+
+        self.known.reserve(old);
+        self.legacy_known |= !self.known_messages.is_empty();
+
+        for hash in self.known_ngrams.drain().chain(self.known_messages.drain()) {
+            self.known.insert(hash);
+        }
     }
+
+    /// Returns true if generated text is a learned message or repeats `overlap` training
+    /// tokens in a row, empty text is considered known too as it's no use anyway
+    fn check_known(&self, generated: &[String], overlap: usize) -> bool {
+        if generated.is_empty() {
+            return true;
+        }
+
+        let hashes = token_hashes(generated);
+        let whole = self.known.contains(run_hash(&hashes) ^ FULL_MESSAGE_SALT)
+            || (self.legacy_known && self.known.contains(Self::legacy_hash(generated)));
+
+        // there are no runs that short, so only whole messages can be found
+        if whole || overlap == 0 {
+            return whole;
+        }
+
+        hashes
+            .windows(overlap)
+            .any(|run| self.known.contains(run_hash(run)))
+            || (self.legacy_known
+                && generated
+                    .windows(overlap)
+                    .any(|run| self.known.contains(Self::legacy_hash(run))))
+    }
+}
+
+// stable across builds unlike the std hasher, as the hashes are saved
+fn token_hashes(tokens: &[String]) -> Vec<u64> {
+    tokens
+        .iter()
+        .map(|token| {
+            token.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+        })
+        .collect()
+}
+
+fn run_hash(token_hashes: &[u64]) -> u64 {
+    token_hashes.iter().fold(0, |hash, token| {
+        hash.wrapping_mul(NGRAM_HASH_BASE).wrapping_add(*token)
+    })
 }
 
 pub(crate) struct Chains {
     inner: Inner,
+
+    // estimated number of bytes taken by chains in memory
+    approx_size: usize,
+    // number of messages learned since chains were saved last time
+    unsaved: usize,
 }
 
 impl Chains {
     // Chains of orders starting from from_ord to to_ord inclusive
     pub(crate) fn new(from_ord: usize, to_ord: usize) -> Self {
         let inner = Inner::new(from_ord, to_ord);
-        Chains { inner }
+        Chains {
+            inner,
+            approx_size: 0,
+            unsaved: 0,
+        }
     }
 
-    fn tokenize(&self, msg: &str) -> Vec<String> {
-        msg.split_whitespace()
-            .map(|s| {
-                s.trim()
-                    .to_lowercase()
-                    .replace(&['\"', ';', ':', '\''][..], " ")
-            })
+    /// Returns number of messages learned since chains were saved last time
+    pub(crate) fn unsaved(&self) -> usize {
+        self.unsaved
+    }
+
+    pub(crate) fn mark_saved(&mut self) {
+        self.unsaved = 0;
+    }
+
+    /// Returns estimated number of bytes taken by chains in memory
+    pub(crate) fn approx_size(&self) -> usize {
+        self.approx_size
+    }
+
+    pub(crate) fn tokenize(msg: &str) -> Vec<String> {
+        tokenizer::tokenize(msg)
+            .iter()
+            .map(|token| token.to_lowercase())
             .collect::<Vec<String>>()
     }
 
-    pub(crate) fn feed(&mut self, msg: &str) -> Vec<String> {
-        let tokens = self.tokenize(msg);
+    fn remember_casing(&mut self, msg: &str) {
+        let mut sentence_start = true;
+
+        for token in tokenizer::tokenize(msg) {
+            let lowercased = token.to_lowercase();
+
+            // first word is capitalized anyway, so it tells nothing about the word itself
+            if !sentence_start {
+                if lowercased == token {
+                    self.inner.casing.remove(&lowercased);
+                } else {
+                    self.inner.casing.insert(lowercased, token.clone());
+                }
+            }
+
+            sentence_start = tokenizer::is_sentence_end(&token);
+        }
+    }
+
+    /// Restores original spelling of generated tokens
+    pub(crate) fn restore_casing(&self, tokens: &[String]) -> Vec<String> {
+        tokens
+            .iter()
+            .map(|token| self.inner.casing.get(token).unwrap_or(token).clone())
+            .collect()
+    }
+
+    fn feed_tokens(&mut self, tokens: &[String]) {
         for chain in &mut self.inner.chains.values_mut() {
-            chain.feed(&tokens);
+            chain.feed(tokens);
+        }
+
+        let reversed = tokens.iter().rev().cloned().collect::<Vec<String>>();
+        for chain in &mut self.inner.backward.values_mut() {
+            chain.feed(&reversed);
+        }
+
+        for token in tokens {
+            *self.inner.token_counts.entry(token.clone()).or_insert(0) += 1;
         }
 
+        self.unsaved += 1;
+
+        let chains_num = self.inner.chains.len() + self.inner.backward.len();
+        self.approx_size += tokens
+            .iter()
+            .map(|token| (token.len() + TOKEN_OVERHEAD) * chains_num)
+            .sum::<usize>();
+    }
+
+    pub(crate) fn feed(&mut self, msg: &str) -> Vec<String> {
+        // personal data must not get into replies, so it isn't learned at all
+        let msg = filter::scrub(msg);
+        let tokens = Self::tokenize(&msg);
+        self.feed_tokens(&tokens);
+
+        self.remember_casing(&msg);
+        let known_size = self.inner.known.size();
         self.inner.remember_known(&tokens);
+        // the index shrinks when it drops its oldest filter
+        self.approx_size = (self.approx_size + self.inner.known.size()).saturating_sub(known_size);
+
         tokens
     }
 
-    fn gen_helper(&self, gen: impl Fn() -> Vec<String>) -> Option<Vec<String>> {
-        // generate until we get something we don't know from learning set
-        let mut rng = rand::thread_rng();
-        for _ in 0..MAX_GEN_RETRIES {
-            let generated = gen();
-            if self.inner.check_known(&generated) {
-                continue;
-            }
-            if rng.gen::<usize>() % 10 == 0 {
-                return Some(generated);
-            }
+    /// Feeds sticker as a message consisting of a single special token,
+    /// so chains generate it as often as the user sends stickers
+    pub(crate) fn feed_sticker(&mut self, file_id: &str, emoji: Option<&str>) {
+        // unlike text, sending the same sticker again is fine,
+        // so it isn't remembered as known message
+        self.feed_tokens(&[tokenizer::sticker_token(file_id)]);
+
+        self.inner
+            .stickers
+            .insert(file_id.to_owned(), emoji.unwrap_or_default().to_owned());
+    }
+
+    /// Returns emoji associated with the sticker, if any
+    pub(crate) fn sticker_emoji(&self, file_id: &str) -> Option<&str> {
+        self.inner
+            .stickers
+            .get(file_id)
+            .map(|emoji| emoji.as_str())
+            .filter(|emoji| !emoji.is_empty())
+    }
+
+    /// Returns how many times the token was fed into chains
+    pub(crate) fn token_count(&self, token: &str) -> usize {
+        self.inner.token_counts.get(token).copied().unwrap_or(0)
+    }
+
+    /// Returns counts of all the tokens fed into chains
+    pub(crate) fn token_counts(&self) -> &HashMap<String, usize> {
+        &self.inner.token_counts
+    }
+
+    /// Counts messages, words and transitions the chains have learned
+    pub(crate) fn stats(&self) -> Counts {
+        let transitions = self
+            .inner
+            .chains
+            .iter()
+            .map(|(order, chain)| (*order, chain.transitions()))
+            .collect();
+
+        let vocabulary = self
+            .inner
+            .token_counts
+            .keys()
+            .filter(|token| tokenizer::sticker_file_id(token).is_none())
+            .count();
+
+        Counts {
+            messages: self.first_chain().map_or(0, |chain| chain.messages()),
+            vocabulary,
+            transitions,
+            // serializing the chains just to measure them is too slow for big chats
+            storage: self.approx_size,
         }
-        None
     }
 
-    pub(crate) fn gen_from_token(&self, token: &str) -> HashMap<usize, Vec<String>> {
-        let mut res = HashMap::new();
-        for (order, chain) in &self.inner.chains {
-            if chain.is_empty() {
-                continue;
-            };
-            match self.gen_helper(|| chain.generate_from_token(token.to_owned())) {
-                Some(generated) => res.insert(*order, generated),
-                None => None,
+    fn first_chain(&self) -> Option<&Chain> {
+        let order = self.inner.chains.keys().min()?;
+        self.inner.chains.get(order)
+    }
+
+    /// Returns log probability that the user would write the message, `vocabulary` is the
+    /// number of distinct tokens all the compared users know, so their scores are comparable
+    pub(crate) fn log_likelihood(&self, tokens: &[String], vocabulary: usize) -> f64 {
+        let chain = match self.first_chain() {
+            Some(chain) => chain,
+            None => return f64::NEG_INFINITY,
+        };
+
+        // message end is counted as a token too
+        let messages = chain.messages();
+        let total = self.inner.token_counts.values().sum::<usize>() + messages;
+        let token_prob = |token: Option<&String>| {
+            let count = match token {
+                Some(token) => self.token_count(token),
+                None => messages,
             };
+            (count + 1) as f64 / (total + vocabulary + 1) as f64
+        };
+
+        chain.log_likelihood(tokens, token_prob)
+    }
+
+    /// Returns share of runs of MIN_OVERLAP tokens in the text which were seen in training,
+    /// texts too short to have such runs are considered new
+    pub(crate) fn known_share(&self, tokens: &[String]) -> f64 {
+        let hashes = token_hashes(tokens);
+        let runs = hashes.windows(MIN_OVERLAP).len();
+        if runs == 0 {
+            return 0.0;
         }
-        res
+
+        let known = hashes
+            .windows(MIN_OVERLAP)
+            .filter(|run| self.inner.known.contains(run_hash(run)))
+            .count();
+        known as f64 / runs as f64
     }
 
-    pub(crate) fn gen_from_empty(&self) -> HashMap<usize, Vec<String>> {
-        let mut res = HashMap::new();
-        for (order, chain) in &self.inner.chains {
-            if chain.is_empty() {
-                continue;
-            };
-            match self.gen_helper(|| chain.generate()) {
-                Some(generated) => res.insert(*order, generated),
-                None => None,
-            };
+    fn gen_helper<R: Rng>(
+        &self,
+        seed: Option<&str>,
+        overlap: usize,
+        bounds: Bounds,
+        rng: &mut R,
+        gen: impl Fn(usize, &mut R) -> Vec<String>,
+    ) -> Option<Vec<String>> {
+        // generate until we get something we don't know from learning set, a token
+        // over the max is generated to tell whether the text would end there,
+        // cutting the text must not drop the seed
+        (0..MAX_GEN_RETRIES)
+            .filter_map(|_| bounds.fit(gen(bounds.max + 1, rng)))
+            .filter(|generated| seed.iter().all(|seed| generated.iter().any(|t| t == seed)))
+            .find(|generated| !self.inner.check_known(generated, overlap))
+    }
+
+    // Generates text containing the token: part before it comes from the backward chain
+    // and part after it from the forward one, so the token may appear anywhere in a sentence
+    fn generate_around(
+        forward: &Seeded,
+        backward: Option<&Seeded>,
+        limit: usize,
+        rng: &mut impl Rng,
+    ) -> Vec<String> {
+        let tail = forward.generate(limit, rng);
+
+        let mut head = match backward {
+            Some(backward) => backward.generate(limit, rng),
+            None => return tail,
+        };
+
+        if head.is_empty() {
+            return tail;
+        }
+
+        // backward chain yields token first and then words preceding it
+        head.reverse();
+        if !tail.is_empty() {
+            head.pop();
+            head.extend(tail);
         }
-        res
+
+        head
+    }
+
+    /// Generates text around the token with the chain of given order, `overlap` is how many
+    /// tokens in a row may be taken from training as is
+    pub(crate) fn gen_from_token(
+        &self,
+        token: &str,
+        order: usize,
+        overlap: usize,
+        bounds: Bounds,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<String>> {
+        let chain = self
+            .inner
+            .chains
+            .get(&order)
+            .filter(|chain| !chain.is_empty())?;
+        let forward = chain.seeded(token);
+        let backward = self
+            .inner
+            .backward
+            .get(&order)
+            .map(|chain| chain.seeded(token));
+
+        // forward chains learn every token, so the token is unknown if they don't have it
+        if forward.is_empty() {
+            return None;
+        }
+
+        self.gen_helper(Some(token), overlap, bounds, rng, |limit, rng| {
+            Self::generate_around(&forward, backward.as_ref(), limit, rng)
+        })
+    }
+
+    pub(crate) fn gen_from_empty(
+        &self,
+        order: usize,
+        overlap: usize,
+        bounds: Bounds,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<String>> {
+        let chain = self
+            .inner
+            .chains
+            .get(&order)
+            .filter(|chain| !chain.is_empty())?;
+        self.gen_helper(None, overlap, bounds, rng, |limit, rng| {
+            chain.generate(limit, rng)
+        })
     }
 
     pub(crate) fn serialize(&self) -> Result<String> {
         serde_yaml::to_string(&self.inner)
     }
 
-    pub(crate) fn deserialize(&mut self, raw: &str) {
-        self.inner = serde_yaml::from_str(raw).unwrap();
+    pub(crate) fn deserialize(&mut self, raw: &str) -> Result<()> {
+        self.inner = serde_yaml::from_str(raw)?;
+        // in-memory representation is about as big as the serialized one
+        self.approx_size = raw.len();
+
+        // data saved before backward chains were introduced, start them from scratch,
+        // until they learn something generation falls back to forward chains only
+        for order in self.inner.chains.keys() {
+            self.inner
+                .backward
+                .entry(*order)
+                .or_insert_with(|| Chain::of_order(*order));
+        }
+
+        self.inner.migrate_known();
+
+        if self.inner.token_counts.is_empty() {
+            // data saved before token counts were introduced, restore them from chains
+            if let Some(chain) = self.first_chain() {
+                self.inner.token_counts = chain.token_counts();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        Chains::tokenize(text)
+    }
+
+    #[test]
+    fn verbatim_runs_are_known() {
+        let mut inner = Inner::new(1, 2);
+        inner.remember_known(&tokens("the quick brown fox jumps over the lazy dog"));
+        inner.remember_known(&tokens("hi there"));
+
+        assert!(inner.check_known(&tokens("a quick brown fox jumps high"), 4));
+        assert!(!inner.check_known(&tokens("a quick brown fox jumps high"), 5));
+        assert!(inner.check_known(&tokens("hi there"), 5));
+        assert!(!inner.check_known(&tokens("hi"), 5));
+        assert!(!inner.check_known(&tokens("brown fox jumps"), 5));
+        assert!(inner.check_known(&[], 5));
+        assert!(!inner.check_known(&tokens("a quick brown fox jumps high"), 0));
+        assert!(inner.check_known(&tokens("hi there"), 0));
+    }
+
+    #[test]
+    fn original_casing_is_restored() {
+        let mut chains = Chains::new(1, 2);
+        chains.feed("Yesterday I met Bob in NYC");
+        chains.feed("Bob is cool. Really cool");
+
+        let cases = [
+            ("i met bob in nyc", "I met Bob in NYC"),
+            // sentence starts tell nothing about casing
+            ("yesterday really", "yesterday really"),
+            ("bob is cool", "Bob is cool"),
+        ];
+
+        for &(generated, expected) in cases.iter() {
+            assert_eq!(
+                chains.restore_casing(&tokens(generated)),
+                expected.split(' ').collect::<Vec<&str>>(),
+                "{}",
+                generated
+            );
+        }
+    }
+
+    #[test]
+    fn long_texts_are_cut_at_sentence_end() {
+        let bounds = Bounds { min: 3, max: 7 };
+
+        assert_eq!(
+            bounds.fit(tokens("hi there. how are you? fine")),
+            Some(tokens("hi there. how are you?"))
+        );
+        assert_eq!(
+            bounds.fit(tokens("well, hi there. how are you doing")),
+            Some(tokens("well, hi there."))
+        );
+        assert_eq!(bounds.fit(tokens("hi. how are you doing today then")), None);
+        assert_eq!(bounds.fit(tokens("hi there")), None);
+        assert_eq!(bounds.fit(tokens("hi there!")), Some(tokens("hi there!")));
+        assert_eq!(
+            bounds.fit(vec![tokenizer::sticker_token("abc")]),
+            Some(vec![tokenizer::sticker_token("abc")])
+        );
+    }
+
+    #[test]
+    fn seed_from_the_middle_of_a_message() {
+        let mut chains = Chains::new(1, 2);
+        chains.feed("a b c d");
+        chains.feed("e b c f");
+        let bounds = Bounds { min: 1, max: 10 };
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for order in 1..=2 {
+            let generated = chains.gen_from_token("c", order, MIN_OVERLAP, bounds, &mut rng);
+            assert!(
+                matches!(generated, Some(ref tokens) if tokens.contains(&"c".to_owned())),
+                "{:?}",
+                generated
+            );
+        }
+        assert_eq!(
+            chains.gen_from_token("z", 2, MIN_OVERLAP, bounds, &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn replies_around_seed_end_naturally() {
+        let mut chains = Chains::new(1, 2);
+        chains.feed("so what do you think about tea and cakes");
+        chains.feed("yes, i do. tea is warm and tasty, and cheap");
+        chains.feed("we drink tea every morning");
+        let bounds = Bounds { min: 2, max: 6 };
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for order in 1..=2 {
+            for _ in 0..50 {
+                let generated = chains
+                    .gen_from_token("tea", order, MAX_OVERLAP, bounds, &mut rng)
+                    .unwrap();
+                let last = generated.last().unwrap();
+
+                assert!(generated.len() <= bounds.max, "{:?}", generated);
+                assert!(generated.contains(&"tea".to_owned()), "{:?}", generated);
+                assert!(
+                    tokenizer::is_sentence_end(last)
+                        || ["cakes", "cheap", "morning"].contains(&last.as_str()),
+                    "{:?}",
+                    generated
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn generation_survives_full_index() {
+        let mut chains = Chains::new(1, 2);
+        // lots of texts learned long ago
+        for hash in 0..(KnownIndex::capacity() as u64 * 3) {
+            chains
+                .inner
+                .known
+                .insert(hash.wrapping_mul(FULL_MESSAGE_SALT));
+        }
+        chains.feed("a b c d");
+        chains.feed("e b c f");
+        let bounds = Bounds { min: 1, max: 10 };
+        let mut rng = StdRng::seed_from_u64(1);
+
+        assert!(chains.inner.check_known(&tokens("a b c d"), MIN_OVERLAP));
+        assert!(chains
+            .gen_from_empty(2, MIN_OVERLAP, bounds, &mut rng)
+            .is_some());
+    }
+
+    #[test]
+    fn oversized_legacy_index_is_dropped() {
+        let mut inner = Inner::new(1, 2);
+        inner.known_ngrams.insert(1);
+        for hash in 0..=KnownIndex::capacity() as u64 {
+            inner
+                .known_messages
+                .insert(hash.wrapping_mul(FULL_MESSAGE_SALT));
+        }
+        inner.migrate_known();
+
+        assert!(!inner.legacy_known);
+        assert!(inner.known.contains(1));
+        assert!(inner.known_messages.is_empty());
+    }
+
+    #[test]
+    fn personal_data_isnt_learned() {
+        let mut chains = Chains::new(1, 2);
+        let learned = chains.feed("write to bob@example.com or call 555 123 4567 now");
+
+        assert_eq!(learned, tokens("write to or call now"));
+        assert_eq!(chains.token_count("555"), 0);
+    }
+
+    #[test]
+    fn broken_data_is_reported() {
+        let mut chains = Chains::new(1, 2);
+        chains.feed("hello there");
+        let raw = chains.serialize().unwrap();
+
+        let mut loaded = Chains::new(1, 2);
+        assert!(loaded.deserialize(&raw).is_ok());
+        assert_eq!(loaded.token_count("hello"), 1);
+
+        assert!(loaded.deserialize("chains: [1, 2").is_err());
+        assert!(loaded.deserialize("chains: 5").is_err());
+    }
+
+    #[test]
+    fn old_index_is_migrated() {
+        let mut inner = Inner::new(1, 2);
+        let old = tokens("the quick brown fox jumps");
+        for i in 0..old.len() {
+            for j in i..=old.len() {
+                inner.known_messages.insert(Inner::legacy_hash(&old[i..j]));
+            }
+        }
+        inner.migrate_known();
+        assert!(inner.known_messages.is_empty());
+
+        assert!(inner.check_known(&tokens("quick brown fox jumps away"), 4));
+        assert!(!inner.check_known(&tokens("quick brown fox jumps away"), 5));
+        // old index can't tell whole messages from their parts
+        assert!(inner.check_known(&tokens("quick brown fox"), 4));
+        assert!(!inner.check_known(&tokens("fox jumps high"), 4));
     }
 }
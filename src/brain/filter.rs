@@ -0,0 +1,170 @@
+use regex::{Captures, Regex, RegexBuilder};
+
+// fewer digits in a row are more likely a date, a time or a price than a phone number
+const PHONE_MIN_DIGITS: usize = 9;
+
+lazy_static::lazy_static! {
+    // personal data users share which must not be learned or repeated
+    static ref PII: Regex = Regex::new(
+        r"(?x)
+          [\w.+-]+@[\w-]+(\.[\w-]+)+
+        | \b(https?://|www\.)\S+
+        | (?P<phone>\+?\(?\d[\d\s().-]{5,}\d)
+        ",
+    )
+    .unwrap();
+}
+
+fn is_pii(caps: &Captures) -> bool {
+    match caps.name("phone") {
+        Some(phone) => {
+            phone.as_str().chars().filter(char::is_ascii_digit).count() >= PHONE_MIN_DIGITS
+        }
+        None => true,
+    }
+}
+
+/// Removes emails, links and phone numbers from the text
+pub(crate) fn scrub(text: &str) -> String {
+    PII.replace_all(text, |caps: &Captures| {
+        if is_pii(caps) {
+            String::new()
+        } else {
+            caps[0].to_owned()
+        }
+    })
+    .into_owned()
+}
+
+fn has_pii(text: &str) -> bool {
+    PII.captures_iter(text).any(|caps| is_pii(&caps))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// blocklist entries in slashes are regexes, other ones are words or phrases
+// which must not match parts of longer words
+fn pattern(entry: &str) -> String {
+    if let Some(regex) = entry.strip_prefix('/').and_then(|e| e.strip_suffix('/')) {
+        if !regex.is_empty() {
+            return format!("(?:{})", regex);
+        }
+    }
+
+    let start = if entry.starts_with(is_word_char) {
+        r"\b"
+    } else {
+        ""
+    };
+    let end = if entry.ends_with(is_word_char) {
+        r"\b"
+    } else {
+        ""
+    };
+    format!("{}{}{}", start, regex::escape(entry), end)
+}
+
+/// Checks a blocklist entry given as a word, a phrase or a regex in slashes
+pub(crate) fn parse_entry(s: &str) -> anyhow::Result<String> {
+    let entry = s.trim();
+    if entry.is_empty() {
+        return Err(anyhow::anyhow!("nothing to block"));
+    }
+
+    let entry = if entry.starts_with('/') {
+        entry.to_owned()
+    } else {
+        entry.to_lowercase()
+    };
+
+    Regex::new(&pattern(&entry)).map_err(|err| anyhow::anyhow!("invalid regex: {}", err))?;
+    Ok(entry)
+}
+
+/// Decides whether generated text may be sent to a chat
+pub(crate) struct ContentFilter {
+    blocked: Option<Regex>,
+}
+
+impl ContentFilter {
+    /// Builds filter blocking entries of the chat blocklist and personal data
+    pub(crate) fn new(blocklist: &[String]) -> Self {
+        let patterns = blocklist
+            .iter()
+            .map(|entry| pattern(entry))
+            .collect::<Vec<String>>();
+
+        let blocked = if patterns.is_empty() {
+            None
+        } else {
+            match RegexBuilder::new(&patterns.join("|"))
+                .case_insensitive(true)
+                .build()
+            {
+                Ok(regex) => Some(regex),
+                Err(err) => {
+                    log::error!("invalid blocklist, only personal data is filtered: {}", err);
+                    None
+                }
+            }
+        };
+
+        ContentFilter { blocked }
+    }
+
+    /// Returns true if the text matches the chat blocklist
+    pub(crate) fn blocks(&self, text: &str) -> bool {
+        matches!(self.blocked, Some(ref blocked) if blocked.is_match(text))
+    }
+
+    pub(crate) fn allows(&self, text: &str) -> bool {
+        !has_pii(text) && !self.blocks(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn personal_data_is_scrubbed() {
+        assert_eq!(
+            scrub("mail me at john.doe@example.com or call +1 (555) 123-4567"),
+            "mail me at  or call "
+        );
+        assert_eq!(
+            scrub("see https://example.com/a?b=c and www.example.org"),
+            "see  and "
+        );
+        assert_eq!(
+            scrub("meet on 2020-11-01 at 10:30, it costs 1 500"),
+            "meet on 2020-11-01 at 10:30, it costs 1 500"
+        );
+    }
+
+    #[test]
+    fn blocklist_matches_words_and_regexes() {
+        let blocklist = ["darn".to_owned(), "/fr[ie]+k/".to_owned()];
+        let filter = ContentFilter::new(&blocklist);
+
+        assert!(!filter.allows("Darn it"));
+        assert!(filter.allows("darnation"));
+        assert!(!filter.allows("what the FRIIK"));
+        assert!(!filter.allows("write to me@example.com"));
+        assert!(filter.allows("have a nice day"));
+        assert!(filter.blocks("Darn it"));
+        assert!(!filter.blocks("write to me@example.com"));
+        assert!(!ContentFilter::new(&["(c)".to_owned()]).allows("copyright (C) 2020"));
+    }
+
+    #[test]
+    fn entries_are_checked() {
+        assert_eq!(parse_entry(" Darn ").unwrap(), "darn");
+        assert_eq!(parse_entry("/Fr[ie]+k/").unwrap(), "/Fr[ie]+k/");
+        assert!(parse_entry("/fr[ie+k/").is_err());
+        assert!(parse_entry("  ").is_err());
+        assert!(parse_entry("a.b (c)").is_ok());
+    }
+}
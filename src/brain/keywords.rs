@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+
+// most frequent words which carry no meaning on their own,
+// replying to them makes the bot look random
+const STOPWORDS_EN: &str = "\
+     a an the and or but if then so as of at by for with about to from in on up out \
+     off over into is are was were be been am do does did have has had i me my you \
+     your he him his she her it its we us our they them their this that these those \
+     what which who not no yes just too very can will would should could there here \
+     all some any ok oh";
+
+const STOPWORDS_RU: &str = "\
+     и в во не что он на я с со как а то все она так его но да ты к у же вы за бы по \
+     только ее мне было вот от меня еще нет о из ему теперь когда даже ну вдруг ли \
+     если уже или ни быть был него до вас нибудь опять уж вам ведь там потом себя \
+     ничего ей может они тут где есть надо ней для мы тебя их чем была сам чтоб без \
+     будто чего раз тоже себе под будет ж тогда кто этот того потому этого какой \
+     совсем ним здесь этом один почти мой тем чтобы нее сейчас были куда зачем всех \
+     никогда можно при наконец два об другой хоть после над больше тот через эти нас \
+     про всего них какая много разве три эту моя впрочем хорошо свою этой перед \
+     иногда лучше чуть том нельзя такой им более всегда конечно всю между это";
+
+lazy_static::lazy_static! {
+    static ref STOPWORDS: HashSet<&'static str> = STOPWORDS_EN
+        .split_whitespace()
+        .chain(STOPWORDS_RU.split_whitespace())
+        .collect();
+}
+
+pub(crate) fn is_stopword(token: &str) -> bool {
+    STOPWORDS.contains(token)
+}
+
+/// Returns spellings of the token to look for in chains: the token as is
+/// and the token without surrounding punctuation, emoji and so on
+fn candidates(token: &str) -> Vec<String> {
+    let mut res = vec![token.to_owned()];
+
+    let trimmed = token.trim_matches(|c: char| !c.is_alphanumeric());
+    if !trimmed.is_empty() && trimmed != token {
+        res.push(trimmed.to_owned());
+    }
+
+    res
+}
+
+/// Picks words of the message to start a reply with, `count` tells how many times a word
+/// is known in the chat, unknown words are skipped and the rarest ones go first
+/// as they are most likely to be meaningful
+pub(crate) fn seeds(tokens: &[String], count: impl Fn(&str) -> usize, limit: usize) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut seeds = Vec::new();
+
+    for token in tokens {
+        for candidate in candidates(token) {
+            if is_stopword(&candidate) || !seen.insert(candidate.clone()) {
+                continue;
+            }
+
+            let count = count(&candidate);
+            if count > 0 {
+                seeds.push((count, candidate));
+            }
+        }
+    }
+
+    // stable sort keeps words with equal counts in message order
+    seeds.sort_by_key(|(count, _)| *count);
+
+    seeds
+        .into_iter()
+        .take(limit)
+        .map(|(_, seed)| seed)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn rarest_known_words_are_seeds() {
+        let counts = [("cat", 2), ("sat", 5), ("the", 10), ("mat", 2), ("dog", 1)]
+            .iter()
+            .copied()
+            .collect::<HashMap<&str, usize>>();
+        let count = |word: &str| counts.get(word).copied().unwrap_or(0);
+
+        let cases: &[(&[&str], usize, &[&str])] = &[
+            (&["the", "cat", "sat"], 3, &["cat", "sat"]),
+            // equally rare words keep message order
+            (&["mat", "sat", "cat"], 3, &["mat", "cat", "sat"]),
+            (&["mat", "sat", "cat", "dog"], 2, &["dog", "mat"]),
+            (&["cat", "cat", "cat"], 3, &["cat"]),
+            (&["the", "a", "zebra"], 3, &[]),
+            // punctuation stuck to a word is trimmed
+            (&["«cat»", "dog!"], 3, &["dog", "cat"]),
+            (&[], 3, &[]),
+        ];
+
+        for (tokens, limit, expected) in cases {
+            let tokens = tokens
+                .iter()
+                .map(|token| token.to_string())
+                .collect::<Vec<String>>();
+            assert_eq!(seeds(&tokens, count, *limit), *expected, "{:?}", tokens);
+        }
+    }
+
+    #[test]
+    fn stopwords_of_both_languages() {
+        for (word, expected) in &[("the", true), ("и", true), ("cat", false), ("кот", false)] {
+            assert_eq!(is_stopword(word), *expected, "{}", word);
+        }
+    }
+}
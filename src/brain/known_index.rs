@@ -0,0 +1,204 @@
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+// bits spent per remembered hash and bits checked per hash, give about 1% false positives
+const BITS_PER_ITEM: usize = 10;
+const PROBES: u64 = 7;
+
+// size of the first filter, of the biggest one and of all the filters of a user together,
+// the biggest filter is half of the index, so there is a full one to keep while it fills up
+const MIN_FILTER_BITS: usize = 1 << 13;
+const MAX_FILTER_BITS: usize = MAX_INDEX_BITS / 2;
+const MAX_INDEX_BITS: usize = 1 << 21;
+
+#[derive(Serialize, Deserialize)]
+struct Filter {
+    #[serde(with = "hex_words")]
+    words: Vec<u64>,
+    items: usize,
+}
+
+impl Filter {
+    fn new(bits: usize) -> Self {
+        Filter {
+            words: vec![0; bits / 64],
+            items: 0,
+        }
+    }
+
+    fn bits(&self) -> usize {
+        self.words.len() * 64
+    }
+
+    fn is_full(&self) -> bool {
+        self.items * BITS_PER_ITEM >= self.bits()
+    }
+
+    // double hashing, second hash is derived from the first one with splitmix64 finalizer
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let mut step = hash ^ (hash >> 30);
+        step = step.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        step ^= step >> 27;
+        step = step.wrapping_mul(0x94d0_49bb_1331_11eb);
+        step ^= step >> 31;
+
+        let bits = self.bits() as u64;
+        (0..PROBES).map(move |i| (hash.wrapping_add(i.wrapping_mul(step | 1)) % bits) as usize)
+    }
+
+    fn insert(&mut self, hash: u64) {
+        for pos in self.positions(hash).collect::<Vec<usize>>() {
+            self.words[pos / 64] |= 1 << (pos % 64);
+        }
+        self.items += 1;
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.positions(hash)
+            .all(|pos| self.words[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+}
+
+/// Bloom filters remembering hashes of learned texts, a filter twice as big is added when
+/// the last one fills up, once the index reaches its size limit the oldest filters are dropped
+/// to make room, so the oldest texts are forgotten rather than false positives piling up
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct KnownIndex {
+    filters: Vec<Filter>,
+}
+
+impl KnownIndex {
+    /// Returns number of bytes taken by the index
+    pub(crate) fn size(&self) -> usize {
+        self.bits() / 8
+    }
+
+    fn bits(&self) -> usize {
+        self.filters.iter().map(|filter| filter.bits()).sum()
+    }
+
+    /// Returns how many hashes the index keeps before it starts forgetting the oldest ones
+    pub(crate) fn capacity() -> usize {
+        MAX_FILTER_BITS / BITS_PER_ITEM
+    }
+
+    /// Makes room for `items` more hashes, so a lot of hashes inserted at once
+    /// go to a single filter of a fitting size
+    pub(crate) fn reserve(&mut self, items: usize) {
+        let last_bits = match self.filters.last() {
+            Some(filter) if !filter.is_full() => return,
+            Some(filter) => filter.bits(),
+            None => 0,
+        };
+
+        let bits = items
+            .saturating_mul(BITS_PER_ITEM)
+            .max(last_bits * 2)
+            .clamp(MIN_FILTER_BITS, MAX_FILTER_BITS)
+            .next_power_of_two();
+
+        while !self.filters.is_empty() && self.bits() + bits > MAX_INDEX_BITS {
+            self.filters.remove(0);
+        }
+        self.filters.push(Filter::new(bits));
+    }
+
+    pub(crate) fn insert(&mut self, hash: u64) {
+        self.reserve(1);
+        // reserve always leaves a filter with some room last
+        if let Some(filter) = self.filters.last_mut() {
+            filter.insert(hash);
+        }
+    }
+
+    /// Returns true if the hash was inserted, or rarely if it wasn't
+    pub(crate) fn contains(&self, hash: u64) -> bool {
+        self.filters.iter().any(|filter| filter.contains(hash))
+    }
+}
+
+// filter bits are saved as a hex string, list of numbers in YAML takes several times more
+mod hex_words {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        words: &[u64],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let hex = words
+            .iter()
+            .map(|word| format!("{:016x}", word))
+            .collect::<String>();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u64>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 16 != 0 || !hex.is_ascii() {
+            return Err(de::Error::custom("malformed filter bits"));
+        }
+
+        (0..hex.len())
+            .step_by(16)
+            .map(|i| u64::from_str_radix(&hex[i..i + 16], 16).map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_inserted_hashes() {
+        let mut index = KnownIndex::default();
+        for hash in 0..10_000u64 {
+            index.insert(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        }
+
+        assert!((0..10_000u64).all(|hash| index.contains(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15))));
+        let false_positives = (10_000..20_000u64)
+            .filter(|hash| index.contains(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        let mut index = KnownIndex::default();
+        assert!(!index.contains(42));
+
+        index.reserve(10);
+        assert_eq!(index.size(), MIN_FILTER_BITS / 8);
+
+        for hash in 0..1_000_000u64 {
+            index.insert(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        }
+        assert!(index.size() <= MAX_INDEX_BITS / 8);
+        assert!(index.size() > MAX_INDEX_BITS / 16);
+        assert!(index.contains(999_999u64.wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+
+        // the oldest hashes are forgotten instead of the index saturating
+        let false_positives = (2_000_000..2_010_000u64)
+            .filter(|hash| index.contains(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn survives_serialization() {
+        let mut index = KnownIndex::default();
+        index.insert(1);
+        index.insert(u64::MAX);
+
+        let raw = serde_yaml::to_string(&index).unwrap();
+        let index = serde_yaml::from_str::<KnownIndex>(&raw).unwrap();
+
+        assert!(index.contains(1));
+        assert!(index.contains(u64::MAX));
+        assert!(serde_yaml::from_str::<KnownIndex>("filters: [{words: abc, items: 1}]").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt::{self, Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use super::chains_pack::Chains;
+use super::keywords;
+
+// replies of that many tokens read best, shorter and longer ones lose points gradually
+const TARGET_TOKENS_MIN: usize = 5;
+const TARGET_TOKENS_MAX: usize = 15;
+
+/// How much each quality of generated text counts when the best of several candidates is picked
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub(crate) struct Weights {
+    pub(crate) length: f64,
+    pub(crate) likelihood: f64,
+    pub(crate) novelty: f64,
+    pub(crate) seed: f64,
+    pub(crate) ending: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            length: 1.0,
+            likelihood: 1.0,
+            novelty: 1.0,
+            seed: 1.0,
+            ending: 1.0,
+        }
+    }
+}
+
+impl Display for Weights {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "length={} likelihood={} novelty={} seed={} ending={}",
+            self.length, self.likelihood, self.novelty, self.seed, self.ending
+        )
+    }
+}
+
+impl Weights {
+    /// Applies changes like 'novelty=2 length=0.5', weights not mentioned are kept
+    pub(crate) fn update(&mut self, changes: &str) -> anyhow::Result<()> {
+        let mut updated = self.clone();
+
+        for change in changes.split_whitespace() {
+            let (name, value) = change
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected name=value, got '{}'", change))?;
+
+            let value = match value.parse::<f64>() {
+                Ok(value) if value.is_finite() && value >= 0.0 => value,
+                _ => return Err(anyhow::anyhow!("weight must be a non-negative number")),
+            };
+
+            let weight = match name {
+                "length" => &mut updated.length,
+                "likelihood" => &mut updated.likelihood,
+                "novelty" => &mut updated.novelty,
+                "seed" => &mut updated.seed,
+                "ending" => &mut updated.ending,
+                other => return Err(anyhow::anyhow!("unknown weight '{}'", other)),
+            };
+            *weight = value;
+        }
+
+        *self = updated;
+        Ok(())
+    }
+}
+
+/// What makes generated text good, each quality is from 0 (worst) to 1 (best)
+#[derive(Debug)]
+pub(crate) struct Qualities {
+    // how close the length is to the target range
+    length: f64,
+    // geometric mean of token probabilities, how natural the text is for its author
+    likelihood: f64,
+    // share of short runs of tokens never seen in training
+    novelty: f64,
+    // share of words from the message being replied to the text contains
+    seed: f64,
+    // text doesn't end with a word like 'the' or 'and'
+    ending: f64,
+}
+
+fn length_quality(len: usize) -> f64 {
+    if len < TARGET_TOKENS_MIN {
+        len as f64 / TARGET_TOKENS_MIN as f64
+    } else if len > TARGET_TOKENS_MAX {
+        TARGET_TOKENS_MAX as f64 / len as f64
+    } else {
+        1.0
+    }
+}
+
+fn ends_with_stopword(tokens: &[String]) -> bool {
+    tokens
+        .iter()
+        .rev()
+        .find(|token| token.chars().any(char::is_alphanumeric))
+        .filter(|word| keywords::is_stopword(word))
+        .is_some()
+}
+
+impl Qualities {
+    /// Rates tokens generated by the chains, `seeds` are words of the message being
+    /// replied to, `vocabulary` is the number of distinct tokens known in the chat
+    pub(crate) fn of(
+        tokens: &[String],
+        seeds: &[String],
+        chains: &Chains,
+        vocabulary: usize,
+    ) -> Self {
+        // end of message counts as a token as well
+        let likelihood = chains.log_likelihood(tokens, vocabulary) / (tokens.len() + 1) as f64;
+
+        let seed = if seeds.is_empty() {
+            1.0
+        } else {
+            let found = seeds.iter().filter(|seed| tokens.contains(seed)).count();
+            found as f64 / seeds.len() as f64
+        };
+
+        Qualities {
+            length: length_quality(tokens.len()),
+            likelihood: likelihood.exp(),
+            novelty: 1.0 - chains.known_share(tokens),
+            seed,
+            ending: if ends_with_stopword(tokens) { 0.0 } else { 1.0 },
+        }
+    }
+
+    pub(crate) fn score(&self, weights: &Weights) -> f64 {
+        self.length * weights.length
+            + self.likelihood * weights.likelihood
+            + self.novelty * weights.novelty
+            + self.seed * weights.seed
+            + self.ending * weights.ending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        Chains::tokenize(text)
+    }
+
+    #[test]
+    fn weights_update() {
+        let mut weights = Weights::default();
+
+        weights.update("novelty=2 length=0.5").unwrap();
+        assert_eq!(weights.novelty, 2.0);
+        assert_eq!(weights.length, 0.5);
+        assert_eq!(weights.seed, 1.0);
+
+        assert!(weights.update("seed=3 color=1").is_err());
+        assert!(weights.update("seed=-1").is_err());
+        assert!(weights.update("seed").is_err());
+        assert_eq!(weights.seed, 1.0);
+
+        assert_eq!(
+            weights.to_string(),
+            "length=0.5 likelihood=1 novelty=2 seed=1 ending=1"
+        );
+    }
+
+    #[test]
+    fn better_texts_score_higher() {
+        let mut chains = Chains::new(1, 2);
+        for text in &[
+            "green tea is nice in the morning",
+            "I like green apples and the morning sun",
+            "apples and tea make a good breakfast",
+        ] {
+            chains.feed(text);
+        }
+
+        let seeds = tokens("tea");
+        let score = |text: &str| {
+            Qualities::of(&tokens(text), &seeds, &chains, 20).score(&Weights::default())
+        };
+
+        assert!(score("green tea make a good breakfast") > score("green apples and the"));
+        assert!(score("green tea is nice in the morning sun") > score("green tea"));
+        assert!(
+            score("I like green tea in the morning") > score("I like green apples in the morning")
+        );
+    }
+}
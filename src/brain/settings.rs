@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use super::chains_pack::{MAX_OVERLAP, MIN_OVERLAP};
+use super::scoring::Weights;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Whom to credit forwarded messages to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ForwardPolicy {
+    /// don't learn from forwarded messages at all
+    Skip,
+    /// learn as if the one who forwarded the message wrote it
+    #[default]
+    Sender,
+    /// learn as words of the original author
+    Author,
+}
+
+impl Display for ForwardPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardPolicy::Skip => write!(f, "skip"),
+            ForwardPolicy::Sender => write!(f, "sender"),
+            ForwardPolicy::Author => write!(f, "author"),
+        }
+    }
+}
+
+impl FromStr for ForwardPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "skip" => Ok(ForwardPolicy::Skip),
+            "sender" => Ok(ForwardPolicy::Sender),
+            "author" => Ok(ForwardPolicy::Author),
+            other => Err(anyhow::anyhow!("unknown forward policy '{}'", other)),
+        }
+    }
+}
+
+// how many training tokens in a row generated text may repeat by default
+const OVERLAP: usize = 5;
+
+fn default_overlap() -> usize {
+    OVERLAP
+}
+
+/// Settings chat members can change for their chat
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ChatSettings {
+    #[serde(default)]
+    pub(crate) forwards: ForwardPolicy,
+    // generated text is dropped if it has that many tokens in a row from training
+    #[serde(default = "default_overlap")]
+    pub(crate) overlap: usize,
+    // how generated candidates are ranked to pick a reply
+    #[serde(default)]
+    pub(crate) weights: Weights,
+    // words and regexes generated text must not contain
+    #[serde(default)]
+    pub(crate) blocked: Vec<String>,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            forwards: ForwardPolicy::default(),
+            overlap: OVERLAP,
+            weights: Weights::default(),
+            blocked: Vec::new(),
+        }
+    }
+}
+
+/// Checks the overlap is one the originality check can look for
+pub(crate) fn parse_overlap(s: &str) -> anyhow::Result<usize> {
+    match s.trim().parse::<usize>() {
+        Ok(overlap) if (MIN_OVERLAP..=MAX_OVERLAP).contains(&overlap) => Ok(overlap),
+        _ => Err(anyhow::anyhow!(
+            "overlap must be a number from {} to {}",
+            MIN_OVERLAP,
+            MAX_OVERLAP
+        )),
+    }
+}
+
+impl ChatSettings {
+    pub(crate) fn serialize(&self) -> serde_yaml::Result<String> {
+        serde_yaml::to_string(self)
+    }
+
+    pub(crate) fn deserialize(raw: &str) -> serde_yaml::Result<Self> {
+        let mut settings: Self = serde_yaml::from_str(raw)?;
+        // saved settings may be edited by hand, so the overlap isn't trusted
+        settings.overlap = settings.overlap.clamp(MIN_OVERLAP, MAX_OVERLAP);
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_overlap_is_kept_in_range() {
+        let cases = [
+            ("overlap: 0", MIN_OVERLAP),
+            ("overlap: 6", 6),
+            ("overlap: 100", MAX_OVERLAP),
+            ("forwards: skip", OVERLAP),
+        ];
+
+        for &(raw, overlap) in cases.iter() {
+            let settings = ChatSettings::deserialize(raw).unwrap();
+            assert_eq!(settings.overlap, overlap, "{}", raw);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+
+use super::{keywords, tokenizer, UserName};
+
+// how many distinctive words to show per user
+const DISTINCTIVE_WORDS: usize = 5;
+
+/// Numbers describing chains of a single user
+#[derive(Default)]
+pub(crate) struct Counts {
+    pub(crate) messages: usize,
+    pub(crate) vocabulary: usize,
+    // distinct transitions per chain order
+    pub(crate) transitions: BTreeMap<usize, usize>,
+    // roughly how many bytes the chains take in Redis, as estimated for memory metrics
+    pub(crate) storage: usize,
+}
+
+/// What the bot knows about a single user
+pub(crate) struct UserStats {
+    pub(crate) name: UserName,
+    pub(crate) counts: Counts,
+    pub(crate) distinctive: Vec<String>,
+}
+
+/// What the bot knows about a chat
+pub(crate) struct ChatStats {
+    pub(crate) users: Vec<UserStats>,
+}
+
+fn is_word(token: &str) -> bool {
+    tokenizer::sticker_file_id(token).is_none()
+        && token.chars().any(|c| c.is_alphanumeric())
+        && !keywords::is_stopword(token)
+}
+
+/// Returns words the user says much more often than the rest of the chat,
+/// frequencies are smoothed so a word used once by nobody else doesn't win
+pub(crate) fn distinctive_words(
+    user: &HashMap<String, usize>,
+    chat: &HashMap<String, usize>,
+) -> Vec<String> {
+    let user_total = user.values().sum::<usize>() as f64;
+    let rest_total = (chat.values().sum::<usize>() + chat.len()) as f64 - user_total;
+
+    let mut scored = user
+        .iter()
+        .filter(|(token, count)| **count > 1 && is_word(token))
+        .map(|(token, count)| {
+            let rest = chat.get(token).copied().unwrap_or(0) - count;
+            let score = (*count as f64 / user_total) / ((rest + 1) as f64 / rest_total);
+            (token, score)
+        })
+        .collect::<Vec<(&String, f64)>>();
+
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    scored
+        .into_iter()
+        .take(DISTINCTIVE_WORDS)
+        .map(|(token, _)| token.clone())
+        .collect()
+}
+
+fn kilobytes(bytes: usize) -> String {
+    format!("{:.1} KB", bytes as f64 / 1024.0)
+}
+
+impl Display for UserStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let transitions = self
+            .counts
+            .transitions
+            .iter()
+            .map(|(order, count)| format!("{} of order {}", count, order))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(
+            f,
+            "{}: {} messages, {} words, transitions: {}, {} in storage",
+            self.name,
+            self.counts.messages,
+            self.counts.vocabulary,
+            transitions,
+            kilobytes(self.counts.storage)
+        )?;
+
+        if !self.distinctive.is_empty() {
+            write!(f, ", distinctive words: {}", self.distinctive.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ChatStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} users, {} messages, {} in storage",
+            self.users.len(),
+            self.users.iter().map(|u| u.counts.messages).sum::<usize>(),
+            kilobytes(self.users.iter().map(|u| u.counts.storage).sum())
+        )?;
+
+        for user in &self.users {
+            write!(f, "\n{}", user)?;
+        }
+
+        Ok(())
+    }
+}
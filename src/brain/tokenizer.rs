@@ -0,0 +1,301 @@
+// tokens which end a sentence, next word starts with a capital letter
+const SENTENCE_END: &[char] = &['.', '!', '?', '…'];
+// no space is put before these tokens
+const CLOSING: &[char] = &[',', '.', '!', '?', '…', ';', ':', ')', ']', '}', '»', '%'];
+// no space is put after these tokens
+const OPENING: &[char] = &['(', '[', '{', '«'];
+// the same character opens and closes a quotation
+const QUOTES: &[char] = &['"', '“', '”', '„'];
+
+const APOSTROPHES: &[char] = &['\'', '’'];
+const HYPHENS: &[char] = &['-'];
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+
+// tokenizer never glues '<' to anything, so text can't produce a sticker token
+const STICKER_PREFIX: &str = "<sticker>";
+
+/// Special token standing for a sticker in chains
+pub(crate) fn sticker_token(file_id: &str) -> String {
+    format!("{}{}", STICKER_PREFIX, file_id)
+}
+
+/// Returns sticker file id if the token stands for a sticker
+pub(crate) fn sticker_file_id(token: &str) -> Option<&str> {
+    token.strip_prefix(STICKER_PREFIX)
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F000..=0x1FAFF
+            | 0x2600..=0x27BF
+            | 0x2300..=0x23FF
+            | 0x2B00..=0x2BFF
+            | 0x2190..=0x21FF
+            | 0x3030
+            | 0x303D
+            | 0x3297
+            | 0x3299
+            | 0xA9
+            | 0xAE
+            | 0x203C
+            | 0x2049
+            | 0x2122
+            | 0x2139
+    )
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    (0x1F1E6..=0x1F1FF).contains(&(c as u32))
+}
+
+// characters which modify preceding emoji: skin tones, variation selectors, tags and so on
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F3FB..=0x1F3FF | 0xFE0E | 0xFE0F | 0x20E3 | 0xE0020..=0xE007F
+    )
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_url(chunk: &str) -> bool {
+    (chunk.starts_with(char::is_alphanumeric) && chunk.contains("://")) || chunk.starts_with("www.")
+}
+
+/// Returns true if the token finishes a sentence
+pub(crate) fn is_sentence_end(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| SENTENCE_END.contains(&c))
+}
+
+fn is_closing(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|c| CLOSING.contains(&c))
+}
+
+fn is_opening(token: &str) -> bool {
+    token.chars().count() == 1 && token.chars().all(|c| OPENING.contains(&c))
+}
+
+fn is_quote(token: &str) -> bool {
+    token.chars().count() == 1 && token.chars().all(|c| QUOTES.contains(&c))
+}
+
+// Splits a chunk without whitespaces into words and punctuation
+fn split_chunk(chunk: &str, tokens: &mut Vec<String>) {
+    if is_url(chunk) {
+        let url = chunk.trim_end_matches(|c: char| CLOSING.contains(&c));
+        tokens.push(url.to_owned());
+        split_chunk(&chunk[url.len()..], tokens);
+        return;
+    }
+
+    let chars = chunk.chars().collect::<Vec<char>>();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let mut token = String::new();
+
+        if is_emoji(c) {
+            // emoji may consist of several code points, all of them go as a single token
+            token.push(c);
+            i += 1;
+
+            if is_regional_indicator(c) && i < chars.len() && is_regional_indicator(chars[i]) {
+                // flags are pairs of regional indicators
+                token.push(chars[i]);
+                i += 1;
+            }
+
+            while i < chars.len() {
+                if is_emoji_modifier(chars[i]) {
+                    token.push(chars[i]);
+                    i += 1;
+                } else if chars[i] == ZERO_WIDTH_JOINER && i + 1 < chars.len() {
+                    token.push(chars[i]);
+                    token.push(chars[i + 1]);
+                    i += 2;
+                } else {
+                    break;
+                }
+            }
+        } else if is_word_char(c) || ((c == '@' || c == '#' || c == '/') && i == 0) {
+            // words, mentions, hashtags and commands
+            token.push(c);
+            i += 1;
+
+            while i < chars.len() {
+                let joins_words = (APOSTROPHES.contains(&chars[i]) || HYPHENS.contains(&chars[i]))
+                    && i + 1 < chars.len()
+                    && is_word_char(chars[i + 1]);
+
+                if is_word_char(chars[i]) || joins_words {
+                    token.push(chars[i]);
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+        } else if SENTENCE_END.contains(&c) {
+            // "...", "?!" and alike go as a single token
+            while i < chars.len() && SENTENCE_END.contains(&chars[i]) {
+                token.push(chars[i]);
+                i += 1;
+            }
+        } else {
+            token.push(c);
+            i += 1;
+        }
+
+        tokens.push(token);
+    }
+}
+
+/// Splits message into words and punctuation marks keeping their original casing
+pub(crate) fn tokenize(msg: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    for chunk in msg.split_whitespace() {
+        split_chunk(chunk, &mut tokens);
+    }
+
+    tokens
+}
+
+fn capitalize(token: &str) -> String {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Joins tokens back into text putting spaces only where they belong
+/// and starting each sentence with a capital letter
+pub(crate) fn detokenize(tokens: &[String]) -> String {
+    let mut res = String::new();
+
+    let mut sentence_start = true;
+    let mut space_before = false;
+    let mut quote_open = false;
+
+    for token in tokens {
+        let mut glue = is_closing(token);
+
+        if is_quote(token) {
+            // closing quote sticks to the previous word, opening one to the next word
+            glue = quote_open;
+            quote_open = !quote_open;
+        }
+
+        if space_before && !glue {
+            res.push(' ');
+        }
+
+        if sentence_start && token.chars().any(char::is_alphanumeric) {
+            res.push_str(&capitalize(token));
+            sentence_start = false;
+        } else {
+            res.push_str(token);
+        }
+
+        if is_sentence_end(token) {
+            sentence_start = true;
+        }
+
+        space_before = !(is_opening(token) || (is_quote(token) && quote_open));
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split(' ').map(|token| token.to_owned()).collect()
+    }
+
+    #[test]
+    fn punctuation_is_split_off() {
+        let cases = [
+            ("Hello, world!", "Hello , world !"),
+            ("wait... what?!", "wait ... what ?!"),
+            ("(see above) ok", "( see above ) ok"),
+            ("don't re-use it", "don't re-use it"),
+            ("«quoted» 50%", "« quoted » 50 %"),
+            ("/say #tag @alice", "/say #tag @alice"),
+            (
+                "see https://example.com/a?b=1.",
+                "see https://example.com/a?b=1 .",
+            ),
+            ("  spaced   out  ", "spaced out"),
+        ];
+
+        for &(text, expected) in cases.iter() {
+            assert_eq!(tokenize(text), tokens(expected), "{}", text);
+        }
+        assert!(tokenize("").is_empty());
+    }
+
+    #[test]
+    fn spaces_are_put_where_they_belong() {
+        let cases = [
+            ("hello , world !", "Hello, world!"),
+            ("wait ... what ?! no", "Wait... What?! No"),
+            ("ok ( see above ) fine", "Ok (see above) fine"),
+            ("he said \" hi there \" .", "He said \"hi there\"."),
+            ("« quoted » 50 %", "«Quoted» 50%"),
+            ("ok : 1 ; 2", "Ok: 1; 2"),
+        ];
+
+        for &(joined, expected) in cases.iter() {
+            assert_eq!(detokenize(&tokens(joined)), expected, "{}", joined);
+        }
+    }
+
+    #[test]
+    fn text_survives_round_trip() {
+        let cases = [
+            "Hello, world! How are you?",
+            "I'd say (probably) yes...",
+            "He said \"no\", then left.",
+            "Привет, как дела?",
+            "Look 👍🏽 nice 🇺🇦 flag",
+        ];
+
+        for &text in cases.iter() {
+            assert_eq!(detokenize(&tokenize(text)), text);
+        }
+    }
+
+    #[test]
+    fn emoji_are_single_tokens() {
+        let cases = [
+            ("nice👍", vec!["nice", "👍"]),
+            ("👍🏽!", vec!["👍🏽", "!"]),
+            ("🇺🇦🇺🇦", vec!["🇺🇦", "🇺🇦"]),
+            ("👨‍👩‍👧 family", vec!["👨‍👩‍👧", "family"]),
+            ("❤️❤️", vec!["❤️", "❤️"]),
+        ];
+
+        for &(text, ref expected) in cases.iter() {
+            assert_eq!(tokenize(text), *expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn text_never_makes_sticker_token() {
+        let token = sticker_token("abc");
+        assert_eq!(sticker_file_id(&token), Some("abc"));
+        assert_eq!(sticker_file_id("abc"), None);
+
+        let tokens = tokenize(&token);
+        assert!(tokens.len() > 1, "{:?}", tokens);
+        assert!(tokens.iter().all(|token| sticker_file_id(token).is_none()));
+    }
+}
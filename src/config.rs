@@ -1,4 +1,21 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use reqwest::Url;
+use tokio::signal::unix::{signal, SignalKind};
+
+// how often to check Redis connection is alive
+const REDIS_HEALTH_CHECK_SEC: &str = "30";
+
+// chat platform to connect to
+const PLATFORM: &str = "telegram";
 
 const REDIS_ADDR: &str = "redis://127.0.0.1:5000/";
 
@@ -11,63 +28,590 @@ const KNOWN_WORD_REPLY_PROB: &str = "0.1";
 
 // maximum number of attempts to generate uniqe and appropriate reply
 const MAX_GEN_RETRIES: &str = "100";
-// basically, the maximum number of words in a generated sentence
+// bounds on the number of words and punctuation marks in a generated reply,
+// longer replies are cut at the end of a sentence
+const MIN_REPLY_TOKENS: &str = "2";
 const MAX_REPLY_TOKENS: &str = "15";
-// how often to dump database into Redis (every 10 new messages by default)
+// how many words from a message to try as a reply seed
+const MAX_SEED_CANDIDATES: &str = "3";
+// how many latest messages per chat wait before being learned, so they still can be edited
+const EDIT_WINDOW: &str = "10";
+// messages waiting for edits are learned after that long even if the window isn't full
+const EDIT_TIMEOUT_SEC: &str = "600";
+// memory chats data may take before the least recently used chats are unloaded
+const MEMORY_BUDGET_MB: &str = "512";
+// chats without messages for that long are unloaded from memory
+const CHAT_IDLE_TIMEOUT_SEC: &str = "86400";
+// how often to dump user data into Redis (every 10 new messages by default)
 const WRITE_TO_REDIS_FREQ: &str = "10";
+// how often to save all unsaved data into Redis
+const FLUSH_INTERVAL_SEC: &str = "300";
+// how many messages the bot may send per second to all chats
+const GLOBAL_SEND_RATE: &str = "30";
+// how many messages the bot may send per minute to a single chat
+const CHAT_SEND_RATE: &str = "20";
+// how many generated replies the bot may post per minute to a single chat
+const REPLIES_PER_MIN: &str = "10";
+// how many times to repeat a message rejected by flood control
+const SEND_RETRY_ATTEMPTS: &str = "3";
+// how long a quiz lasts before the answer is revealed
+const QUIZ_DURATION_SEC: &str = "60";
+// how many users to choose from in a quiz
+const QUIZ_OPTIONS: &str = "4";
+// path webhook listener accepts updates on
+const WEBHOOK_PATH: &str = "/";
+
+/// Chat platforms the bot can work with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Platform {
+    Telegram,
+    Matrix,
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "telegram" => Ok(Platform::Telegram),
+            "matrix" => Ok(Platform::Matrix),
+            other => Err(anyhow::anyhow!("unknown platform '{}'", other)),
+        }
+    }
+}
+
+/// Bot settings, read from a config file, environment variables and command line flags,
+/// each next source overrides the previous one
 pub(crate) struct Config {
+    pub(crate) platform: Platform,
+
     pub(crate) redis_addr: String,
     pub(crate) redis_passwd: Option<String>,
+    pub(crate) redis_health_check_sec: u64,
 
     pub(crate) reply_timeout_sec: u64,
     pub(crate) reply_prob: f64,
     pub(crate) known_word_reply_prob: f64,
 
     pub(crate) max_gen_retries: usize,
+    pub(crate) min_reply_tokens: usize,
     pub(crate) max_reply_tokens: usize,
+    pub(crate) max_seed_candidates: usize,
+    pub(crate) edit_window: usize,
+    pub(crate) edit_timeout_sec: u64,
+    pub(crate) memory_budget_mb: usize,
+    pub(crate) chat_idle_timeout_sec: u64,
     pub(crate) write_to_redis_freq: usize,
+    pub(crate) flush_interval_sec: u64,
+
+    pub(crate) global_send_rate: usize,
+    pub(crate) chat_send_rate: usize,
+    pub(crate) replies_per_min: usize,
+    pub(crate) send_retry_attempts: usize,
+
+    pub(crate) quiz_duration_sec: u64,
+    pub(crate) quiz_options: usize,
+
+    // makes replies reproducible, random if not set
+    pub(crate) seed: Option<u64>,
+
+    // metrics are served over HTTP only if address is set
+    pub(crate) metrics_addr: Option<SocketAddr>,
+
+    // webhook mode is used instead of long polling if listen address is set
+    pub(crate) webhook_addr: Option<SocketAddr>,
+    pub(crate) webhook_path: String,
+    pub(crate) webhook_secret: Option<String>,
+    // public url to register the webhook with, might be done manually otherwise
+    pub(crate) webhook_url: Option<String>,
+
+    pub(crate) telegram_bot_token: Option<String>,
+
+    pub(crate) matrix_homeserver: Option<Url>,
+    pub(crate) matrix_access_token: Option<String>,
+}
+
+// collects values from all the sources along with the problems found in them
+struct Loader {
+    file: HashMap<String, String>,
+    args: HashMap<String, String>,
+    read: HashSet<&'static str>,
+    errors: Vec<String>,
+}
+
+impl Loader {
+    fn new(args: &[String]) -> Self {
+        let mut loader = Loader {
+            file: HashMap::new(),
+            args: HashMap::new(),
+            read: HashSet::new(),
+            errors: Vec::new(),
+        };
+
+        loader.parse_args(args);
+        loader.read.insert("CONFIG_FILE");
+
+        if let Some(path) = loader.raw("CONFIG_FILE") {
+            match read_file(Path::new(&path)) {
+                Ok(file) => loader.file = file,
+                Err(err) => loader.errors.push(format!("{:#}", err)),
+            }
+        }
+
+        loader
+    }
+
+    // flags are setting names in lower case with dashes, e.g. --reply-prob-default 0.5
+    fn parse_args(&mut self, args: &[String]) {
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    self.errors.push(format!("unexpected argument '{}'", arg));
+                    continue;
+                }
+            };
+
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, value.to_owned()),
+                None => match args.next() {
+                    Some(value) => (flag, value.clone()),
+                    None => {
+                        self.errors.push(format!("no value for --{}", flag));
+                        continue;
+                    }
+                },
+            };
+
+            self.args
+                .insert(name.replace('-', "_").to_uppercase(), value);
+        }
+    }
+
+    fn raw(&self, key: &str) -> Option<String> {
+        self.args
+            .get(key)
+            .cloned()
+            .or_else(|| env::var(key).ok())
+            .or_else(|| self.file.get(key).cloned())
+    }
+
+    fn optional<T>(&mut self, key: &'static str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.read.insert(key);
+
+        match self.raw(key)?.parse::<T>() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(format!("unable parse {}: {}", key, err));
+                None
+            }
+        }
+    }
+
+    fn value<T>(&mut self, key: &'static str, default: &str) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.optional(key).unwrap_or_else(|| {
+            default
+                .parse::<T>()
+                .unwrap_or_else(|_| panic!("invalid default for {}", key))
+        })
+    }
+
+    fn check(&mut self, ok: bool, problem: &str) {
+        if !ok {
+            self.errors.push(problem.to_owned());
+        }
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        let mut unknown = self
+            .file
+            .keys()
+            .chain(self.args.keys())
+            .filter(|key| !self.read.contains(key.as_str()))
+            .map(|key| format!("unknown setting {}", key))
+            .collect::<Vec<_>>();
+        unknown.sort();
+        unknown.dedup();
+        self.errors.extend(unknown);
+
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow::anyhow!(
+            "invalid config:\n  {}",
+            self.errors.join("\n  ")
+        ))
+    }
+}
+
+// config file keys are the same as environment variables, in either case,
+// the format is chosen by file extension
+fn read_file(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("unable read config file {}", path.display()))?;
+
+    let is_toml = path.extension() == Some("toml".as_ref());
+    let values = if is_toml {
+        parse_toml(&raw)
+    } else {
+        parse_yaml(&raw)
+    };
+
+    values.with_context(|| format!("unable parse config file {}", path.display()))
+}
+
+fn parse_toml(raw: &str) -> anyhow::Result<HashMap<String, String>> {
+    let table: HashMap<String, toml::Value> = toml::from_str(raw)?;
+    let mut values = HashMap::new();
+
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Float(f) => f.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            _ => anyhow::bail!("{} is not a string or a number", key),
+        };
+        values.insert(key.to_uppercase(), value);
+    }
+
+    Ok(values)
+}
 
-    pub(crate) telegram_bot_token: String,
+fn parse_yaml(raw: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mapping: HashMap<String, serde_yaml::Value> = serde_yaml::from_str(raw)?;
+    let mut values = HashMap::new();
+
+    for (key, value) in mapping {
+        let value = match value {
+            serde_yaml::Value::String(s) => s,
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::Bool(b) => b.to_string(),
+            _ => anyhow::bail!("{} is not a string or a number", key),
+        };
+        values.insert(key.to_uppercase(), value);
+    }
+
+    Ok(values)
+}
+
+// settings which can't change without restarting the bot are kept from the old config
+fn keep<T: PartialEq + Clone>(key: &str, new: &mut T, old: &T) {
+    if new != old {
+        log::warn!("{} can't be changed without restart, ignored", key);
+        *new = old.clone();
+    }
 }
 
 impl Config {
-    pub(crate) fn new() -> Self {
-        Config {
-            redis_addr: env::var("REDIS_ADDR").unwrap_or_else(|_| REDIS_ADDR.to_owned()),
-            redis_passwd: env::var("REDIS_PASSWD").ok(),
-
-            reply_timeout_sec: env::var("REPLY_TIMEOUT_SEC")
-                .unwrap_or_else(|_| REPLY_TIMEOUT_DEFAULT_SEC.to_owned())
-                .parse::<u64>()
-                .expect("unable parse REPLY_TIMEOUT_SEC"),
-
-            reply_prob: env::var("REPLY_PROB_DEFAULT")
-                .unwrap_or_else(|_| REPLY_PROB_DEFAULT.to_owned())
-                .parse::<f64>()
-                .expect("unable parse REPLY_PROB_DEFAULT"),
-
-            known_word_reply_prob: env::var("KNOWN_WORD_REPLY_PROB")
-                .unwrap_or_else(|_| KNOWN_WORD_REPLY_PROB.to_owned())
-                .parse::<f64>()
-                .expect("unable parse KNOWN_WORD_REPLY_PROB"),
-
-            max_gen_retries: env::var("MAX_GEN_RETRIES")
-                .unwrap_or_else(|_| MAX_GEN_RETRIES.to_owned())
-                .parse::<usize>()
-                .expect("unable parse MAX_GEN_RETRIES"),
-
-            max_reply_tokens: env::var("MAX_REPLY_TOKENS")
-                .unwrap_or_else(|_| MAX_REPLY_TOKENS.to_owned())
-                .parse::<usize>()
-                .expect("unable parse MAX_REPLY_TOKENS"),
-
-            write_to_redis_freq: env::var("write_to_redis_FREQ")
-                .unwrap_or_else(|_| WRITE_TO_REDIS_FREQ.to_owned())
-                .parse::<usize>()
-                .expect("unable parse WRITE_TO_REDIS_FREQ"),
-
-            telegram_bot_token: env::var("TELEGRAM_BOT_TOKEN").expect("TELEGRAM_BOT_TOKEN not set"),
+    /// Reads the config, `args` are command line flags without the program name,
+    /// returned error lists all the problems found
+    pub(crate) fn load(args: &[String]) -> anyhow::Result<Self> {
+        let mut l = Loader::new(args);
+
+        let config = Config {
+            platform: l.value("PLATFORM", PLATFORM),
+
+            redis_addr: l.value("REDIS_ADDR", REDIS_ADDR),
+            redis_passwd: l.optional("REDIS_PASSWD"),
+            redis_health_check_sec: l.value("REDIS_HEALTH_CHECK_SEC", REDIS_HEALTH_CHECK_SEC),
+
+            reply_timeout_sec: l.value("REPLY_TIMEOUT_SEC", REPLY_TIMEOUT_DEFAULT_SEC),
+            reply_prob: l.value("REPLY_PROB_DEFAULT", REPLY_PROB_DEFAULT),
+            known_word_reply_prob: l.value("KNOWN_WORD_REPLY_PROB", KNOWN_WORD_REPLY_PROB),
+
+            max_gen_retries: l.value("MAX_GEN_RETRIES", MAX_GEN_RETRIES),
+            min_reply_tokens: l.value("MIN_REPLY_TOKENS", MIN_REPLY_TOKENS),
+            max_reply_tokens: l.value("MAX_REPLY_TOKENS", MAX_REPLY_TOKENS),
+            max_seed_candidates: l.value("MAX_SEED_CANDIDATES", MAX_SEED_CANDIDATES),
+            edit_window: l.value("EDIT_WINDOW", EDIT_WINDOW),
+            edit_timeout_sec: l.value("EDIT_TIMEOUT_SEC", EDIT_TIMEOUT_SEC),
+            memory_budget_mb: l.value("MEMORY_BUDGET_MB", MEMORY_BUDGET_MB),
+            chat_idle_timeout_sec: l.value("CHAT_IDLE_TIMEOUT_SEC", CHAT_IDLE_TIMEOUT_SEC),
+            write_to_redis_freq: l.value("WRITE_TO_REDIS_FREQ", WRITE_TO_REDIS_FREQ),
+            flush_interval_sec: l.value("FLUSH_INTERVAL_SEC", FLUSH_INTERVAL_SEC),
+
+            global_send_rate: l.value("GLOBAL_SEND_RATE", GLOBAL_SEND_RATE),
+            chat_send_rate: l.value("CHAT_SEND_RATE", CHAT_SEND_RATE),
+            replies_per_min: l.value("REPLIES_PER_MIN", REPLIES_PER_MIN),
+            send_retry_attempts: l.value("SEND_RETRY_ATTEMPTS", SEND_RETRY_ATTEMPTS),
+
+            quiz_duration_sec: l.value("QUIZ_DURATION_SEC", QUIZ_DURATION_SEC),
+            quiz_options: l.value("QUIZ_OPTIONS", QUIZ_OPTIONS),
+
+            seed: l.optional("SEED"),
+
+            metrics_addr: l.optional("METRICS_ADDR"),
+
+            webhook_addr: l.optional("WEBHOOK_ADDR"),
+            webhook_path: l.value("WEBHOOK_PATH", WEBHOOK_PATH),
+            webhook_secret: l.optional("WEBHOOK_SECRET"),
+            webhook_url: l.optional("WEBHOOK_URL"),
+
+            telegram_bot_token: l.optional("TELEGRAM_BOT_TOKEN"),
+
+            matrix_homeserver: l.optional("MATRIX_HOMESERVER"),
+            matrix_access_token: l.optional("MATRIX_ACCESS_TOKEN"),
+        };
+
+        for &(key, prob) in &[
+            ("REPLY_PROB_DEFAULT", config.reply_prob),
+            ("KNOWN_WORD_REPLY_PROB", config.known_word_reply_prob),
+        ] {
+            l.check(
+                (0.0..=1.0).contains(&prob),
+                &format!("{} must be within 0..=1, got {}", key, prob),
+            );
+        }
+        l.check(
+            config.flush_interval_sec > 0,
+            "FLUSH_INTERVAL_SEC must be positive",
+        );
+        l.check(
+            config.redis_health_check_sec > 0,
+            "REDIS_HEALTH_CHECK_SEC must be positive",
+        );
+        l.check(
+            config.max_gen_retries > 0,
+            "MAX_GEN_RETRIES must be positive",
+        );
+        l.check(
+            config.min_reply_tokens > 0 && config.min_reply_tokens <= config.max_reply_tokens,
+            "MIN_REPLY_TOKENS must be positive and not greater than MAX_REPLY_TOKENS",
+        );
+        l.check(config.quiz_options >= 2, "QUIZ_OPTIONS must be at least 2");
+        l.check(
+            config.webhook_path.starts_with('/'),
+            "WEBHOOK_PATH must start with /",
+        );
+        match config.platform {
+            Platform::Telegram => l.check(
+                config.telegram_bot_token.is_some(),
+                "TELEGRAM_BOT_TOKEN must be set for telegram platform",
+            ),
+            Platform::Matrix => {
+                l.check(
+                    config.matrix_homeserver.is_some(),
+                    "MATRIX_HOMESERVER must be set for matrix platform",
+                );
+                l.check(
+                    config.matrix_access_token.is_some(),
+                    "MATRIX_ACCESS_TOKEN must be set for matrix platform",
+                );
+            }
         }
+
+        l.finish()?;
+        Ok(config)
+    }
+
+    // copies settings which are only read on start from the running config
+    fn keep_structural(&mut self, old: &Config) {
+        keep("PLATFORM", &mut self.platform, &old.platform);
+        keep("REDIS_ADDR", &mut self.redis_addr, &old.redis_addr);
+        keep("REDIS_PASSWD", &mut self.redis_passwd, &old.redis_passwd);
+        keep(
+            "REDIS_HEALTH_CHECK_SEC",
+            &mut self.redis_health_check_sec,
+            &old.redis_health_check_sec,
+        );
+        keep(
+            "FLUSH_INTERVAL_SEC",
+            &mut self.flush_interval_sec,
+            &old.flush_interval_sec,
+        );
+        keep(
+            "GLOBAL_SEND_RATE",
+            &mut self.global_send_rate,
+            &old.global_send_rate,
+        );
+        keep(
+            "CHAT_SEND_RATE",
+            &mut self.chat_send_rate,
+            &old.chat_send_rate,
+        );
+        keep(
+            "REPLIES_PER_MIN",
+            &mut self.replies_per_min,
+            &old.replies_per_min,
+        );
+        keep("SEED", &mut self.seed, &old.seed);
+        keep("METRICS_ADDR", &mut self.metrics_addr, &old.metrics_addr);
+        keep("WEBHOOK_ADDR", &mut self.webhook_addr, &old.webhook_addr);
+        keep("WEBHOOK_PATH", &mut self.webhook_path, &old.webhook_path);
+        keep(
+            "WEBHOOK_SECRET",
+            &mut self.webhook_secret,
+            &old.webhook_secret,
+        );
+        keep("WEBHOOK_URL", &mut self.webhook_url, &old.webhook_url);
+        keep(
+            "TELEGRAM_BOT_TOKEN",
+            &mut self.telegram_bot_token,
+            &old.telegram_bot_token,
+        );
+        keep(
+            "MATRIX_HOMESERVER",
+            &mut self.matrix_homeserver,
+            &old.matrix_homeserver,
+        );
+        keep(
+            "MATRIX_ACCESS_TOKEN",
+            &mut self.matrix_access_token,
+            &old.matrix_access_token,
+        );
+    }
+}
+
+// flags the config is loaded with if `Shared::init` wasn't called,
+// tests don't connect anywhere, so they make up the credentials
+#[cfg(not(test))]
+const DEFAULT_ARGS: &[&str] = &[];
+#[cfg(test)]
+const DEFAULT_ARGS: &[&str] = &["--telegram-bot-token=test"];
+
+/// Config shared by the whole bot, can be reloaded while the bot is running
+#[derive(Default)]
+pub(crate) struct Shared {
+    args: RwLock<Vec<String>>,
+    current: RwLock<Option<Arc<Config>>>,
+}
+
+impl Shared {
+    /// Loads the config with given command line flags, they are kept for reloads
+    pub(crate) fn init(&self, args: Vec<String>) -> anyhow::Result<()> {
+        let config = Config::load(&args)?;
+
+        *self.args.write().unwrap() = args;
+        *self.current.write().unwrap() = Some(Arc::new(config));
+        Ok(())
+    }
+
+    /// Returns the current config, it's loaded without command line flags if `init`
+    /// wasn't called before
+    pub(crate) fn get(&self) -> Arc<Config> {
+        if let Some(ref config) = *self.current.read().unwrap() {
+            return config.clone();
+        }
+
+        let mut current = self.current.write().unwrap();
+        current
+            .get_or_insert_with(|| {
+                let args = DEFAULT_ARGS
+                    .iter()
+                    .map(|&arg| arg.to_owned())
+                    .collect::<Vec<_>>();
+                Arc::new(Config::load(&args).unwrap_or_else(|err| panic!("{}", err)))
+            })
+            .clone()
+    }
+
+    /// Reads all the sources again, the running config stays untouched if
+    /// the new one is invalid
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let mut config = Config::load(&self.args.read().unwrap())?;
+        config.keep_structural(&self.get());
+
+        *self.current.write().unwrap() = Some(Arc::new(config));
+        Ok(())
+    }
+
+    /// Reloads the config every time SIGHUP is received
+    pub(crate) async fn reload_on_hangup(&self) {
+        let mut hangup = signal(SignalKind::hangup()).expect("unable to install SIGHUP handler");
+
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(()) => log::info!("SIGHUP received, config reloaded"),
+                Err(err) => log::error!("SIGHUP received, config is not reloaded: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let path = env::temp_dir().join("mimic-bot-config-test.toml");
+        fs::write(&path, "max_reply_tokens = 20\nmax_gen_retries = 7\n").unwrap();
+
+        let config = Config::load(&args(&[
+            "--telegram-bot-token=test",
+            "--config-file",
+            path.to_str().unwrap(),
+            "--max-reply-tokens=30",
+        ]))
+        .unwrap();
+
+        assert_eq!(config.max_reply_tokens, 30);
+        assert_eq!(config.max_gen_retries, 7);
+    }
+
+    #[test]
+    fn yaml_file() {
+        let values = parse_yaml("chat_send_rate: 5\nwebhook_path: /hook\n").unwrap();
+
+        assert_eq!(values["CHAT_SEND_RATE"], "5");
+        assert_eq!(values["WEBHOOK_PATH"], "/hook");
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let err = Config::load(&args(&[
+            "--known-word-reply-prob",
+            "1.5",
+            "--max-seed-candidates=many",
+            "--webhook-path=hook",
+            "--no-such-setting=1",
+            "--platform=matrix",
+            "--matrix-homeserver=https://matrix.example.org",
+        ]))
+        .err()
+        .unwrap()
+        .to_string();
+
+        for problem in &[
+            "KNOWN_WORD_REPLY_PROB must be within 0..=1",
+            "unable parse MAX_SEED_CANDIDATES",
+            "WEBHOOK_PATH must start with /",
+            "unknown setting NO_SUCH_SETTING",
+            "MATRIX_ACCESS_TOKEN must be set for matrix platform",
+        ] {
+            assert!(err.contains(problem), "{} not found in: {}", problem, err);
+        }
+    }
+
+    #[test]
+    fn structural_settings_are_kept_on_reload() {
+        let old = Config::load(&args(DEFAULT_ARGS)).unwrap();
+        let mut new = Config::load(&args(&[
+            "--telegram-bot-token=test",
+            "--webhook-path=/new",
+            "--max-reply-tokens=42",
+        ]))
+        .unwrap();
+
+        new.keep_structural(&old);
+
+        assert_eq!(new.webhook_path, old.webhook_path);
+        assert_eq!(new.max_reply_tokens, 42);
     }
 }
@@ -0,0 +1,118 @@
+use std::io;
+
+use reqwest::StatusCode;
+
+use super::transport::RetryAfter;
+
+/// What to do about an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Severity {
+    /// network hiccups, flood control or platform outage, worth trying again later
+    Transient,
+    /// the chat doesn't accept bot messages: bot is kicked, has no rights,
+    /// message to reply to is deleted and so on, the message should be skipped
+    Chat,
+    /// the bot can't work at all, e.g. its token is revoked
+    Fatal,
+}
+
+// telegram-bot hides error kinds, so telegram errors are told apart by their descriptions
+const FATAL_MESSAGES: &[&str] = &["unauthorized", "m_unknown_token"];
+const CHAT_MESSAGES: &[&str] = &[
+    "forbidden",
+    "bad request",
+    "chat not found",
+    "not found",
+    "kicked",
+    "blocked",
+    "rights",
+    "deactivated",
+];
+
+fn status_severity(status: StatusCode) -> Severity {
+    if status == StatusCode::UNAUTHORIZED {
+        Severity::Fatal
+    } else if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Severity::Transient
+    } else {
+        Severity::Chat
+    }
+}
+
+/// Tells how bad the error is, unknown errors are considered transient
+pub(crate) fn classify(err: &anyhow::Error) -> Severity {
+    for cause in err.chain() {
+        if cause.is::<RetryAfter>() || cause.is::<io::Error>() {
+            return Severity::Transient;
+        }
+
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = err.status() {
+                return status_severity(status);
+            }
+            return Severity::Transient;
+        }
+    }
+
+    let msg = err.to_string().to_lowercase();
+
+    if FATAL_MESSAGES.iter().any(|m| msg.contains(m)) {
+        Severity::Fatal
+    } else if CHAT_MESSAGES.iter().any(|m| msg.contains(m)) {
+        Severity::Chat
+    } else {
+        Severity::Transient
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn telegram_errors() {
+        let cases = [
+            ("Unauthorized", Severity::Fatal),
+            (
+                "Forbidden: bot was kicked from the group chat",
+                Severity::Chat,
+            ),
+            ("Bad Request: message to reply not found", Severity::Chat),
+            (
+                "Bad Request: have no rights to send a message",
+                Severity::Chat,
+            ),
+            ("Internal Server Error", Severity::Transient),
+            ("error trying to connect: dns error", Severity::Transient),
+        ];
+
+        for &(msg, severity) in cases.iter() {
+            assert_eq!(classify(&anyhow::anyhow!(msg)), severity, "{}", msg);
+        }
+    }
+
+    #[test]
+    fn typed_errors() {
+        let flood = anyhow::Error::from(RetryAfter(Duration::from_secs(1)));
+        assert_eq!(classify(&flood), Severity::Transient);
+
+        let io = anyhow::Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "forbidden"));
+        assert_eq!(classify(&io), Severity::Transient);
+    }
+
+    #[test]
+    fn http_statuses() {
+        assert_eq!(status_severity(StatusCode::UNAUTHORIZED), Severity::Fatal);
+        assert_eq!(status_severity(StatusCode::FORBIDDEN), Severity::Chat);
+        assert_eq!(
+            status_severity(StatusCode::BAD_GATEWAY),
+            Severity::Transient
+        );
+        assert_eq!(
+            status_severity(StatusCode::TOO_MANY_REQUESTS),
+            Severity::Transient
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use telegram_bot::ChatId;
+
+/// Sliding window allowing at most `limit` events during `period`, zero limit means no limit
+pub(crate) struct Window {
+    limit: usize,
+    period: Duration,
+    events: VecDeque<Instant>,
+}
+
+impl Window {
+    pub(crate) fn new(limit: usize, period: Duration) -> Self {
+        Window {
+            limit,
+            period,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&first) = self.events.front() {
+            if now.duration_since(first) < self.period {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+
+    /// Returns how long to wait before the next event fits into the window
+    pub(crate) fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.expire(now);
+
+        if self.limit == 0 || self.events.len() < self.limit {
+            return None;
+        }
+
+        Some(self.events[0] + self.period - now)
+    }
+
+    pub(crate) fn record(&mut self, now: Instant) {
+        if self.limit != 0 {
+            self.events.push_back(now);
+        }
+    }
+
+    /// Records the event if it fits into the window, returns false otherwise
+    pub(crate) fn try_record(&mut self, now: Instant) -> bool {
+        if self.wait_time(now).is_some() {
+            return false;
+        }
+        self.record(now);
+        true
+    }
+
+    /// Returns true if no events happened during the last period
+    pub(crate) fn is_idle(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        self.events.is_empty()
+    }
+}
+
+/// Limits how many replies the bot generates per chat in a minute
+pub(crate) struct Cooldown {
+    limit: usize,
+    chats: HashMap<ChatId, Window>,
+}
+
+impl Cooldown {
+    pub(crate) fn new(limit: usize) -> Self {
+        Cooldown {
+            limit,
+            chats: HashMap::new(),
+        }
+    }
+
+    /// Returns true and counts the reply if the chat hasn't run out of replies yet
+    pub(crate) fn try_reply(&mut self, chat_id: ChatId) -> bool {
+        let now = Instant::now();
+        let limit = self.limit;
+
+        self.chats.retain(|_, window| !window.is_idle(now));
+        self.chats
+            .entry(chat_id)
+            .or_insert_with(|| Window::new(limit, Duration::from_secs(60)))
+            .try_record(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_waits_for_the_oldest_event_to_expire() {
+        let start = Instant::now();
+        let mut window = Window::new(2, Duration::from_secs(10));
+
+        assert!(window.try_record(start));
+        assert!(window.try_record(start + Duration::from_secs(4)));
+        assert_eq!(
+            window.wait_time(start + Duration::from_secs(6)),
+            Some(Duration::from_secs(4))
+        );
+        assert!(window.try_record(start + Duration::from_secs(10)));
+        assert!(!window.is_idle(start + Duration::from_secs(19)));
+        assert!(window.is_idle(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn zero_limit_is_unlimited() {
+        let now = Instant::now();
+        let mut window = Window::new(0, Duration::from_secs(10));
+
+        for _ in 0..100 {
+            assert!(window.try_record(now));
+        }
+    }
+
+    #[test]
+    fn cooldown_is_per_chat() {
+        let mut cooldown = Cooldown::new(1);
+
+        assert!(cooldown.try_reply(ChatId::new(1)));
+        assert!(!cooldown.try_reply(ChatId::new(1)));
+        assert!(cooldown.try_reply(ChatId::new(2)));
+    }
+}
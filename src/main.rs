@@ -2,47 +2,421 @@
 
 mod brain;
 mod config;
+mod errors;
+mod limits;
+mod metrics;
+mod quiz;
+mod requests;
+mod shutdown;
+#[cfg(test)]
+mod tests;
+mod transport;
+mod webhook;
 
 use futures::StreamExt;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use redis::{aio::ConnectionManager, IntoConnectionInfo};
 use reqwest::{redirect::Policy, Url};
-use std::{thread, time, time::SystemTime};
-use telegram_bot::*;
-
-use brain::{Brain, UserName};
-use config::Config;
+use std::{env, time::Duration, time::SystemTime};
+use telegram_bot::{Api, ChatId};
+use tokio::sync::oneshot;
+
+use brain::{
+    filter,
+    settings::{self, ForwardPolicy},
+    Brain, Reply, UserName,
+};
+use config::Platform;
+use errors::Severity;
+use limits::Cooldown;
+use metrics::METRICS;
+use quiz::{Quiz, Quizzes, VoteResult};
+use requests::SetWebhook;
+use transport::{
+    matrix::MatrixTransport, telegram::TelegramTransport, throttled::Throttled, Content, Event,
+    IncomingMessage, Transport, Vote,
+};
 
 lazy_static::lazy_static! {
-    static ref CONFIG: Config = Config::new();
+    static ref CONFIG: config::Shared = Default::default();
+}
+
+// delays between attempts to connect to Redis on start, doubled after each attempt
+const REDIS_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REDIS_RETRY_DELAY_MAX: Duration = Duration::from_secs(30);
+const REDIS_RETRY_ATTEMPTS: usize = 10;
+
+// how many times to try generating a text sentence for a quiz
+const QUIZ_GEN_ATTEMPTS: usize = 10;
+// how often to check whether it's time to reveal quiz answers
+const QUIZ_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// how often to send replies put off because of rate limits
+const SEND_QUEUE_INTERVAL: Duration = Duration::from_millis(250);
+// how many likely authors /whosaid shows
+const WHOSAID_TOP: usize = 3;
+// how many players to show in the leaderboard
+const LEADERBOARD_SIZE: usize = 10;
+
+// delays between attempts to get updates after an error
+const UPDATES_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const UPDATES_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Returns name of the user the message should be learned as,
+/// or None if it shouldn't be learned at all
+fn credited_name(brain: &Brain, message: &IncomingMessage) -> Option<UserName> {
+    let author = match message.forwarded_from {
+        Some(ref author) => author,
+        None => return Some(message.sender.clone()),
+    };
+
+    match brain.settings(message.chat_id).forwards {
+        ForwardPolicy::Skip => None,
+        ForwardPolicy::Sender => Some(message.sender.clone()),
+        ForwardPolicy::Author => Some(author.clone()),
+    }
+}
+
+async fn learn_text(brain: &mut Brain, message: &IncomingMessage, text: &str) {
+    if let Some(name) = credited_name(brain, message) {
+        if brain.is_known_user(message.chat_id, &name) {
+            brain
+                .feed_live_message(message.chat_id, message.id, name, text)
+                .await;
+        }
+    }
+}
+
+// Sends generated reply on behalf of the user, `trailer` is put after it,
+// in a message of its own if the reply is a sticker
+async fn send_reply<T: Transport>(
+    transport: &T,
+    cooldown: &mut Cooldown,
+    message: &IncomingMessage,
+    name: UserName,
+    reply: Reply,
+    trailer: Option<&str>,
+) -> anyhow::Result<()> {
+    if !cooldown.try_reply(message.chat_id) {
+        log::debug!(
+            "too many replies to chat {}, reply skipped",
+            message.chat_id
+        );
+        return Ok(());
+    }
+
+    let trailer = trailer.unwrap_or_default();
+
+    match reply {
+        Reply::Text(text) => {
+            transport
+                .reply_text(message, &format!("{}: {} {}", name, text, trailer))
+                .await?;
+        }
+        Reply::Sticker { file_id, emoji } => {
+            let sent = transport.reply_sticker(message, &file_id).await;
+
+            match (sent, emoji) {
+                (Ok(()), _) if !trailer.is_empty() => {
+                    transport.reply_text(message, trailer).await?;
+                }
+                (Ok(()), _) => {}
+                // sticker may be gone along with its set, emoji is the next best thing
+                (Err(_), Some(emoji)) => {
+                    transport
+                        .reply_text(message, &format!("{}: {} {}", name, emoji, trailer))
+                        .await?;
+                }
+                (Err(err), None) => return Err(err),
+            }
+        }
+    }
+
+    METRICS.reply_sent(message.chat_id);
+    Ok(())
+}
+
+/// Parses '/say order --seed number' into the chain order and the seed, both are optional
+/// but at least one of them must be given
+fn parse_say(text: &str) -> Option<(usize, Option<u64>)> {
+    let mut words = text.split_whitespace().skip(1).peekable();
+    words.peek()?;
+
+    let mut order = 1;
+    let mut seed = None;
+
+    while let Some(word) = words.next() {
+        if word == "--seed" {
+            seed = Some(words.next()?.parse().ok()?);
+        } else {
+            order = word.parse().unwrap_or(1);
+        }
+    }
+
+    Some((order, seed))
+}
+
+/// Replies to an ordinary chat message with some probability
+async fn reply_passive<T: Transport>(
+    transport: &T,
+    brain: &Brain,
+    cooldown: &mut Cooldown,
+    rng: &mut StdRng,
+    message: &IncomingMessage,
+    text: &str,
+) -> anyhow::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    if now - message.date as u64 > CONFIG.get().reply_timeout_sec {
+        // don't reply to message older than REPLY_EXPIRE_TIME_SEC
+        return Ok(());
+    }
+
+    if let Some((name, resp)) = brain.gen_from_message(message.chat_id, text, 2, rng) {
+        // we've generated message based on some word from the message
+        if rng.gen::<f64>() <= CONFIG.get().known_word_reply_prob {
+            send_reply(transport, cooldown, message, name, resp, None).await?;
+        }
+    } else if let Some((name, resp)) = brain.gen_from_empty(message.chat_id, 2, rng) {
+        // just generate a random message
+        if rng.gen::<f64>() <= CONFIG.get().reply_prob {
+            send_reply(transport, cooldown, message, name, resp, None).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Posts a sentence generated from a random user's chains and asks the chat who might say it
+async fn start_quiz<T: Transport>(
+    transport: &T,
+    brain: &Brain,
+    quizzes: &mut Quizzes,
+    rng: &mut StdRng,
+    message: &IncomingMessage,
+) -> anyhow::Result<()> {
+    let chat_id = message.chat_id;
+
+    if quizzes.is_running(chat_id) {
+        transport
+            .reply_text(
+                message,
+                "Quiz is already running, use '/guess number' to vote or /reveal to finish it",
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let users = brain.users(chat_id);
+    if users.len() < 2 {
+        transport
+            .reply_text(message, "Quiz needs at least two learned users")
+            .await?;
+        return Ok(());
+    }
+
+    // stickers make poor questions, so only texts are asked about
+    let generated =
+        (0..QUIZ_GEN_ATTEMPTS).find_map(|_| match brain.gen_from_empty(chat_id, 2, rng) {
+            Some((name, Reply::Text(text))) => Some((name, text)),
+            _ => None,
+        });
+
+    let (answer, text) = match generated {
+        Some(generated) => generated,
+        None => {
+            transport
+                .reply_text(message, "Unable to generate a sentence for the quiz")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let quiz = Quiz::new(
+        message.clone(),
+        answer,
+        users,
+        CONFIG.get().quiz_options,
+        rng,
+    );
+    let choices = quizzes.start(quiz).choices();
+
+    transport
+        .reply_choices(
+            message,
+            &format!(
+                "Who said it?\n\n{}\n\nVote with the buttons or '/guess number'",
+                text
+            ),
+            &choices,
+        )
+        .await
+}
+
+/// Reveals the answer of the quiz running in the chat and gives points to the winners
+async fn finish_quiz<T: Transport>(
+    transport: &T,
+    brain: &mut Brain,
+    quizzes: &mut Quizzes,
+    chat_id: ChatId,
+) -> anyhow::Result<()> {
+    let quiz = match quizzes.finish(chat_id) {
+        Some(quiz) => quiz,
+        None => return Ok(()),
+    };
+
+    let winners = quiz.winners();
+
+    // the chat may have been unloaded while the quiz was running
+    brain.load_chat(chat_id).await?;
+    if let Err(err) = brain.add_points(chat_id, &winners).await {
+        log::error!("error saving leaderboard of chat {}: {}", chat_id, err);
+    }
+
+    let text = if winners.is_empty() {
+        format!("It was {}! Nobody guessed right", quiz.answer)
+    } else {
+        format!(
+            "It was {}! Guessed right: {}",
+            quiz.answer,
+            winners
+                .iter()
+                .map(|name| name.0.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+    };
+
+    transport.reply_text(&quiz.question, &text).await
+}
+
+// tells whether the sender may change chat settings, replies to those who may not
+async fn check_admin<T: Transport>(
+    transport: &T,
+    message: &IncomingMessage,
+) -> anyhow::Result<bool> {
+    if transport.is_admin(message).await? {
+        return Ok(true);
+    }
+
+    transport
+        .reply_text(message, "Only chat admins can change chat settings")
+        .await?;
+    Ok(false)
 }
 
-const REDIS_RETRY_DELAY: time::Duration = time::Duration::from_millis(5000);
-const REDIS_RETRY_ATTEMPTS: usize = 5;
+// adds entry to the chat blocklist or removes it, only chat admins may do that
+async fn change_blocklist<T: Transport>(
+    transport: &T,
+    brain: &mut Brain,
+    message: &IncomingMessage,
+    entry: Option<&str>,
+    block: bool,
+) -> anyhow::Result<()> {
+    let chat_id = message.chat_id;
+
+    let entry = match entry.map(str::trim) {
+        Some(entry) if !entry.is_empty() => entry,
+        _ => {
+            let blocked = brain.settings(chat_id).blocked;
+            let text = if blocked.is_empty() {
+                "Nothing is blocked, use '/block word' or '/block /regex/' to block it".to_owned()
+            } else {
+                format!(
+                    "Replies never contain: {}, use '/unblock word' to allow it again",
+                    blocked.join(", ")
+                )
+            };
+            transport.reply_text(message, &text).await?;
+            return Ok(());
+        }
+    };
+
+    if !transport.is_admin(message).await? {
+        transport
+            .reply_text(message, "Only chat admins can change the blocklist")
+            .await?;
+        return Ok(());
+    }
+
+    let entry = match filter::parse_entry(entry) {
+        Ok(entry) => entry,
+        Err(err) => {
+            transport.reply_text(message, &format!("{}", err)).await?;
+            return Ok(());
+        }
+    };
+
+    let changed = if block {
+        brain.block(chat_id, entry.clone()).await
+    } else {
+        brain.unblock(chat_id, &entry).await
+    };
 
-fn full_name(first_name: &str, last_name: Option<String>) -> String {
-    match last_name {
-        Some(last_name) => format!("{} {}", first_name, last_name),
-        None => first_name.to_owned(),
+    let text = match changed {
+        Ok(true) if block => format!("'{}' is blocked now", entry),
+        Ok(true) => format!("'{}' isn't blocked anymore", entry),
+        Ok(false) if block => format!("'{}' is blocked already", entry),
+        Ok(false) => format!("'{}' isn't blocked", entry),
+        Err(err) => format!("Error saving chat settings, reason: {}", err),
+    };
+    transport.reply_text(message, &text).await?;
+    Ok(())
+}
+
+fn vote_answer(result: VoteResult) -> &'static str {
+    match result {
+        VoteResult::Counted => "Vote counted",
+        VoteResult::AlreadyVoted => "You have already voted",
+        VoteResult::Rejected => "This quiz is over or there is no such option",
     }
 }
 
-async fn handle_messages(api: Api, brain: &mut Brain, message: Message) -> Result<(), Error> {
-    let chat_id = api.send(message.chat.get_chat()).await?.id();
+async fn handle_vote<T: Transport>(
+    transport: &T,
+    quizzes: &mut Quizzes,
+    vote: Vote,
+) -> anyhow::Result<()> {
+    let text = match quiz::parse_vote(&vote.data) {
+        Some((quiz_id, choice)) => {
+            vote_answer(quizzes.vote(vote.chat_id, Some(quiz_id), vote.voter.clone(), choice))
+        }
+        None => "Unknown button",
+    };
+
+    transport.answer_vote(&vote, text).await
+}
+
+async fn handle_messages<T: Transport>(
+    transport: &T,
+    brain: &mut Brain,
+    cooldown: &mut Cooldown,
+    quizzes: &mut Quizzes,
+    rng: &mut StdRng,
+    message: IncomingMessage,
+) -> anyhow::Result<()> {
+    let chat_id = message.chat_id;
 
     // try to read data for the given chat_id
-    if let Err(err) = brain.read_from_redis(chat_id).await {
-        api.send(message.text_reply(format!("Error loading chat data, reason: {}", err)))
+    if let Err(err) = brain.load_chat(chat_id).await {
+        transport
+            .reply_text(
+                &message,
+                &format!("Error loading chat data, reason: {}", err),
+            )
             .await?;
     };
 
-    if let MessageKind::Text { ref data, .. } = message.kind {
+    if let Content::Text(ref data) = message.content {
         let msg_text = data.as_str();
 
         if msg_text.starts_with("/learn") {
             let parts = msg_text.splitn(2, "/learn ").collect::<Vec<&str>>();
 
             if parts.len() < 2 {
-                api.send(message.text_reply("Wrong syntax, use '/learn url_to_json user_name"))
+                transport
+                    .reply_text(&message, "Wrong syntax, use '/learn url_to_json user_name")
                     .await?;
                 // we don't care of this error anymore
                 return Ok(());
@@ -51,7 +425,8 @@ async fn handle_messages(api: Api, brain: &mut Brain, message: Message) -> Resul
             let (uri, one_user) = match parts[1].trim().split_once(" ") {
                 Some((uri, one_user)) => (uri, Some(UserName(one_user.to_owned()))),
                 None => {
-                    api.send(message.text_reply("User name must be provided"))
+                    transport
+                        .reply_text(&message, "User name must be provided")
                         .await?;
                     // we don't care of this error anymore
                     return Ok(());
@@ -61,13 +436,15 @@ async fn handle_messages(api: Api, brain: &mut Brain, message: Message) -> Resul
             let uri: Url = match uri.trim().parse() {
                 Ok(uri) => uri,
                 Err(_) => {
-                    api.send(message.text_reply(format!("Error parsing uri: {}", uri)))
+                    transport
+                        .reply_text(&message, &format!("Error parsing uri: {}", uri))
                         .await?;
                     return Ok(());
                 }
             };
 
-            api.send(message.text_reply("Downloading history data"))
+            transport
+                .reply_text(&message, "Downloading history data")
                 .await?;
 
             let client = reqwest::Client::builder()
@@ -79,7 +456,8 @@ async fn handle_messages(api: Api, brain: &mut Brain, message: Message) -> Resul
             let res = match client.get(uri).send().await {
                 Ok(raw_data) => raw_data,
                 Err(err) => {
-                    api.send(message.text_reply(format!("Error downloading uri: {:?}", err)))
+                    transport
+                        .reply_text(&message, &format!("Error downloading uri: {:?}", err))
                         .await?;
                     return Ok(());
                 }
@@ -88,143 +466,549 @@ async fn handle_messages(api: Api, brain: &mut Brain, message: Message) -> Resul
             let parsed = match res.json::<brain::types::Source>().await {
                 Ok(parsed) => parsed,
                 Err(err) => {
-                    api.send(message.text_reply(format!("Error parsing josn: {:?}", err)))
+                    transport
+                        .reply_text(&message, &format!("Error parsing josn: {:?}", err))
                         .await?;
                     return Ok(());
                 }
             };
 
-            api.send(message.text_reply("Download completed")).await?;
-            api.send(message.text_reply("Learning...")).await?;
+            transport.reply_text(&message, "Download completed").await?;
+            transport.reply_text(&message, "Learning...").await?;
 
             match brain.learn_from_hist(chat_id, parsed, one_user).await {
-                Ok(proccessed) => {
-                    api.send(message.text_reply(format!(
-                        "Done learning, {} messages proccessed!",
-                        proccessed
-                    )))
-                    .await?;
+                Ok(learned) if learned.interrupted => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!(
+                        "Learning interrupted by bot shutdown, {} messages proccessed and saved",
+                        learned.messages
+                    ),
+                        )
+                        .await?;
+                }
+                Ok(learned) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!("Done learning, {} messages proccessed!", learned.messages),
+                        )
+                        .await?;
                 }
                 Err(err) => {
-                    api.send(message.text_reply(format!("Error learning, reason: {}", err)))
+                    transport
+                        .reply_text(&message, &format!("Error learning, reason: {}", err))
                         .await?;
                 }
             }
         } else if msg_text.starts_with("/say") {
-            let parts = msg_text.split("/say ").collect::<Vec<&str>>();
-            if parts.len() < 2 {
-                api.send(message.text_reply("Wrong syntax, use '/say order (from 1 to 2)'"))
+            let (order, seed) = match parse_say(msg_text) {
+                Some(parsed) => parsed,
+                None => {
+                    transport
+                        .reply_text(
+                            &message,
+                            "Wrong syntax, use '/say order (from 1 to 2) [--seed number]'",
+                        )
+                        .await?;
+                    // we don't care of that error anymore
+                    return Ok(());
+                }
+            };
+
+            // the same seed gives the same reply as long as the chat learns nothing new,
+            // so it's shown to let users get a reply they liked again
+            let seed = seed.unwrap_or_else(|| rng.gen());
+            let trailer = format!("(seed {})", seed);
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            if let Some((name, resp)) = brain.gen_from_empty(chat_id, order, &mut rng) {
+                send_reply(transport, cooldown, &message, name, resp, Some(&trailer)).await?;
+            }
+        } else if msg_text.starts_with("/quiz") {
+            start_quiz(transport, brain, quizzes, rng, &message).await?;
+        } else if msg_text.starts_with("/guess") {
+            let choice = msg_text
+                .split_once(' ')
+                .and_then(|(_, choice)| choice.trim().parse::<usize>().ok());
+
+            let text = match choice {
+                _ if !quizzes.is_running(chat_id) => "No quiz is running, start one with /quiz",
+                Some(choice) => {
+                    vote_answer(quizzes.vote(chat_id, None, message.sender.clone(), choice))
+                }
+                None => "Wrong syntax, use '/guess number'",
+            };
+            transport.reply_text(&message, text).await?;
+        } else if msg_text.starts_with("/reveal") {
+            if quizzes.is_running(chat_id) {
+                finish_quiz(transport, brain, quizzes, chat_id).await?;
+            } else {
+                transport
+                    .reply_text(&message, "No quiz is running, start one with /quiz")
                     .await?;
-                // we don't care of that error anymore
+            }
+        } else if msg_text.starts_with("/leaderboard") {
+            let leaderboard = brain.leaderboard(chat_id);
+
+            let text = if leaderboard.is_empty() {
+                "Nobody has scored yet, start a quiz with /quiz".to_owned()
+            } else {
+                leaderboard
+                    .iter()
+                    .take(LEADERBOARD_SIZE)
+                    .enumerate()
+                    .map(|(i, (name, points))| format!("{}. {}: {}", i + 1, name, points))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            };
+            transport.reply_text(&message, &text).await?;
+        } else if msg_text.starts_with("/whosaid") {
+            let text = match msg_text.split_once(' ') {
+                Some((_, quote)) => quote.trim(),
+                None => {
+                    transport
+                        .reply_text(&message, "Wrong syntax, use '/whosaid text'")
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            let authors = brain.whosaid(chat_id, text, WHOSAID_TOP);
+
+            let reply = if authors.is_empty() {
+                "Nobody is learned in this chat yet".to_owned()
+            } else {
+                authors
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, prob))| format!("{}. {}: {:.1}%", i + 1, name, prob * 100.0))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            };
+            transport.reply_text(&message, &reply).await?;
+        } else if msg_text.starts_with("/stats") {
+            let only = msg_text
+                .split_once(' ')
+                .map(|(_, name)| UserName::from(name.trim().trim_start_matches('@')))
+                .filter(|name| !name.0.is_empty());
+
+            let stats = brain.stats(chat_id, only.as_ref());
+            let text = if stats.users.is_empty() {
+                match only {
+                    Some(name) => format!("Nothing is known about {}", name),
+                    None => "Nothing is learned in this chat yet".to_owned(),
+                }
+            } else if only.is_some() {
+                stats.users[0].to_string()
+            } else {
+                stats.to_string()
+            };
+            transport.reply_text(&message, &text).await?;
+        } else if msg_text.starts_with("/forwards") {
+            let parts = msg_text.splitn(2, ' ').collect::<Vec<&str>>();
+
+            if parts.len() < 2 {
+                transport.reply_text(&message, &format!(
+                    "Forwarded messages are credited to: {}, use '/forwards skip|sender|author' to change it",
+                    brain.settings(chat_id).forwards
+                ))
+                .await?;
                 return Ok(());
             }
 
-            let order = parts[parts.len() - 1].parse::<usize>().unwrap_or(1);
+            if !check_admin(transport, &message).await? {
+                return Ok(());
+            }
 
-            if let Some((name, resp)) = brain.gen_from_empty(chat_id, order) {
-                api.send(message.text_reply(format!("{}: {} ", name, resp)))
-                    .await?;
+            let policy = match parts[1].parse::<ForwardPolicy>() {
+                Ok(policy) => policy,
+                Err(err) => {
+                    transport.reply_text(&message, &format!("{}", err)).await?;
+                    return Ok(());
+                }
+            };
+
+            match brain.set_forward_policy(chat_id, policy).await {
+                Ok(()) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!("Forwarded messages policy set to: {}", policy),
+                        )
+                        .await?;
+                }
+                Err(err) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!("Error saving chat settings, reason: {}", err),
+                        )
+                        .await?;
+                }
             }
-        } else if !data.is_empty() {
-            let full_name = UserName(full_name(
-                &message.from.first_name,
-                message.from.last_name.clone(),
-            ));
+        } else if msg_text.starts_with("/overlap") {
+            let parts = msg_text.splitn(2, ' ').collect::<Vec<&str>>();
 
-            if brain.is_known_user(chat_id, &full_name) {
-                brain.feed_message(chat_id, full_name, data, true).await;
+            if parts.len() < 2 {
+                transport
+                    .reply_text(
+                        &message,
+                        &format!(
+                            "Replies with {} words in a row from learned messages are dropped, use '/overlap number' to change it",
+                            brain.settings(chat_id).overlap
+                        ),
+                    )
+                    .await?;
+                return Ok(());
             }
 
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if now - message.date as u64 > CONFIG.reply_timeout_sec {
-                // don't reply to message older than REPLY_EXPIRE_TIME_SEC
+            if !check_admin(transport, &message).await? {
                 return Ok(());
             }
 
-            let parts = data.split_whitespace().collect::<Vec<&str>>();
-            let mut rng = rand::thread_rng();
+            let overlap = match settings::parse_overlap(parts[1]) {
+                Ok(overlap) => overlap,
+                Err(err) => {
+                    transport.reply_text(&message, &format!("{}", err)).await?;
+                    return Ok(());
+                }
+            };
 
-            if let Some((name, resp)) = brain.gen_from_token(chat_id, parts[parts.len() - 1], 2) {
-                // we've generated message base on the last word
-                if rng.gen::<f64>() <= CONFIG.known_word_reply_prob {
-                    api.send(message.text_reply(format!("{}: {} ", name, resp)))
+            match brain.set_overlap(chat_id, overlap).await {
+                Ok(()) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!(
+                                "Replies with {} words in a row from learned messages are dropped now",
+                                overlap
+                            ),
+                        )
+                        .await?;
+                }
+                Err(err) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!("Error saving chat settings, reason: {}", err),
+                        )
+                        .await?;
+                }
+            }
+        } else if msg_text.starts_with("/weights") {
+            let mut weights = brain.settings(chat_id).weights;
+
+            let changes = match msg_text.split_once(' ') {
+                Some((_, changes)) if !changes.trim().is_empty() => changes,
+                _ => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!(
+                                "Replies are ranked with weights {}, use '/weights name=value' to change them",
+                                weights
+                            ),
+                        )
                         .await?;
+                    return Ok(());
                 }
-            } else if let Some((name, resp)) = brain.gen_from_empty(chat_id, 2) {
-                // just generate a random message
-                if rng.gen::<f64>() <= CONFIG.reply_prob {
-                    api.send(message.text_reply(format!("{}: {} ", name, resp)))
+            };
+
+            if !check_admin(transport, &message).await? {
+                return Ok(());
+            }
+
+            if let Err(err) = weights.update(changes) {
+                transport.reply_text(&message, &format!("{}", err)).await?;
+                return Ok(());
+            }
+
+            let text = format!("Replies are ranked with weights {} now", weights);
+
+            match brain.set_weights(chat_id, weights).await {
+                Ok(()) => {
+                    transport.reply_text(&message, &text).await?;
+                }
+                Err(err) => {
+                    transport
+                        .reply_text(
+                            &message,
+                            &format!("Error saving chat settings, reason: {}", err),
+                        )
                         .await?;
                 }
             }
+        } else if msg_text.starts_with("/block") || msg_text.starts_with("/unblock") {
+            let entry = msg_text.split_once(' ').map(|(_, entry)| entry);
+            let block = msg_text.starts_with("/block");
+            change_blocklist(transport, brain, &message, entry, block).await?;
+        } else if !data.is_empty() {
+            learn_text(brain, &message, data).await;
+            reply_passive(transport, brain, cooldown, rng, &message, data).await?;
+        }
+    } else if let Content::Sticker {
+        ref file_id,
+        ref emoji,
+    } = message.content
+    {
+        if let Some(name) = credited_name(brain, &message) {
+            if brain.is_known_user(chat_id, &name) {
+                brain
+                    .feed_sticker(chat_id, name, file_id, emoji.as_deref())
+                    .await;
+            }
         }
+
+        // emoji of the sticker is the only text we have to seed a reply
+        let emoji = emoji.as_deref().unwrap_or_default();
+        reply_passive(transport, brain, cooldown, rng, &message, emoji).await?;
+    } else if let Content::Caption(ref caption) = message.content {
+        learn_text(brain, &message, caption).await;
+        reply_passive(transport, brain, cooldown, rng, &message, caption).await?;
     }
 
     Ok(())
 }
 
-async fn try_get_connection(
-    client: &redis::Client,
-    mut try_no: usize,
-) -> redis::RedisResult<redis::aio::Connection> {
-    loop {
-        let con = client.get_async_connection().await;
+/// Applies edit to the message if it is not learned yet
+async fn handle_edit(brain: &mut Brain, message: IncomingMessage) {
+    let chat_id = message.chat_id;
 
-        if let Err(ref err) = con {
-            try_no -= 1;
-            if try_no == 0 {
-                return con;
-            }
+    if let Err(err) = brain.load_chat(chat_id).await {
+        log::error!("error loading data for chat {}: {}", chat_id, err);
+        return;
+    }
 
-            log::error!("error connecting to Redis: {}, retry in 5 seconds...", err);
-            thread::sleep(REDIS_RETRY_DELAY);
-        } else {
-            return con;
+    if let Some(text) = message.text() {
+        if !text.starts_with('/') && !brain.edit_message(chat_id, message.id, text) {
+            log::debug!(
+                "message {} in chat {} is already learned, edit ignored",
+                message.id,
+                chat_id
+            );
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    pretty_env_logger::init();
+// fatal errors are passed on to stop the bot, the rest is logged and skipped
+fn skip_error(err: anyhow::Error, what: &str) -> anyhow::Result<()> {
+    metrics::inc(&METRICS.message_errors);
 
-    let api = Api::new(&CONFIG.telegram_bot_token);
-
-    let redis_url = redis::parse_redis_url(&CONFIG.redis_addr).expect("unable to parse Redis url");
-    let redis_conn_info = redis::ConnectionInfo {
-        addr: Box::new(redis::ConnectionAddr::Tcp(
-            redis_url.host().unwrap().to_string(),
-            redis_url.port().unwrap(),
-        )),
-        db: 0,
-        username: None,
-        passwd: CONFIG.redis_passwd.clone(),
-    };
+    match errors::classify(&err) {
+        Severity::Fatal => return Err(err),
+        Severity::Chat => log::warn!("{} skipped: {}", what, err),
+        Severity::Transient => log::error!("error handling {}: {}", what, err),
+    }
+
+    Ok(())
+}
 
-    let client = redis::Client::open(redis_conn_info);
-    if let Err(ref err) = client {
-        panic!("error opening Redis connection: {}", err);
+/// Connects to Redis given by url, the connection is restored automatically if it drops later
+async fn connect_redis(url: &str) -> anyhow::Result<ConnectionManager> {
+    let mut info = url.into_connection_info()?;
+    if let Some(ref passwd) = CONFIG.get().redis_passwd {
+        info.passwd = Some(passwd.clone());
     }
 
-    let con = try_get_connection(&client.unwrap(), REDIS_RETRY_ATTEMPTS).await;
-    if let Err(err) = con {
-        panic!("error connecting to Redis: {}", err);
+    let mut delay = REDIS_RETRY_DELAY;
+    let mut try_no = 1;
+
+    loop {
+        match ConnectionManager::new(info.clone()).await {
+            Ok(con) => return Ok(con),
+            Err(err) if try_no < REDIS_RETRY_ATTEMPTS => {
+                log::error!(
+                    "error connecting to Redis: {}, retry in {:?}...",
+                    err,
+                    delay
+                );
+                tokio::time::delay_for(delay).await;
+                delay = (delay * 2).min(REDIS_RETRY_DELAY_MAX);
+                try_no += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
     }
+}
 
-    let mut brain = Brain::new(1, 2).set_redis_con(con.unwrap());
+async fn handle_updates<T: Transport>(
+    transport: &T,
+    brain: &mut Brain,
+    mut shutdown: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut stream = transport.updates();
+    let mut cooldown = Cooldown::new(CONFIG.get().replies_per_min);
+    let mut quizzes = Quizzes::new();
+    let mut rng = match CONFIG.get().seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut quiz_timer = tokio::time::interval(QUIZ_CHECK_INTERVAL);
+    let mut send_timer = tokio::time::interval(SEND_QUEUE_INTERVAL);
+    let mut flush_timer =
+        tokio::time::interval(Duration::from_secs(CONFIG.get().flush_interval_sec));
+    let mut redis_check_timer =
+        tokio::time::interval(Duration::from_secs(CONFIG.get().redis_health_check_sec));
+    let mut backoff = UPDATES_BACKOFF_MIN;
 
-    // Fetch new updates via long poll method
-    let mut stream = api.stream();
+    loop {
+        tokio::select! {
+            event = stream.next() => {
+                let event = match event {
+                    Some(Ok(event)) => {
+                        metrics::inc(&METRICS.updates);
+                        backoff = UPDATES_BACKOFF_MIN;
+                        event
+                    }
+                    Some(Err(err)) => {
+                        metrics::inc(&METRICS.update_errors);
+                        if errors::classify(&err) == Severity::Fatal {
+                            return Err(err);
+                        }
+
+                        // the stream reconnects on the next poll, give the platform some time to recover
+                        log::error!("error receiving updates: {}, retry in {:?}", err, backoff);
+                        tokio::select! {
+                            _ = tokio::time::delay_for(backoff) => {}
+                            _ = &mut shutdown => break,
+                        }
+                        backoff = (backoff * 2).min(UPDATES_BACKOFF_MAX);
+                        continue;
+                    }
+                    None => break,
+                };
+
+                match event {
+                    Event::Message(message) => {
+                        let (id, chat_id) = (message.id, message.chat_id);
+
+                        let res = handle_messages(
+                            transport,
+                            brain,
+                            &mut cooldown,
+                            &mut quizzes,
+                            &mut rng,
+                            message,
+                        )
+                        .await;
+                        if let Err(err) = res {
+                            skip_error(err, &format!("message {} in chat {}", id, chat_id))?;
+                        }
+                    }
+                    Event::Edit(message) => handle_edit(brain, message).await,
+                    Event::Vote(vote) => {
+                        let chat_id = vote.chat_id;
+
+                        if let Err(err) = handle_vote(transport, &mut quizzes, vote).await {
+                            skip_error(err, &format!("vote in chat {}", chat_id))?;
+                        }
+                    }
+                }
+            }
+            _ = quiz_timer.tick() => {
+                let duration = Duration::from_secs(CONFIG.get().quiz_duration_sec);
 
-    while let Some(update) = stream.next().await {
-        // If the received update contains a new message...
-        let update = update?;
-        if let UpdateKind::Message(message) = update.kind {
-            handle_messages(api.clone(), &mut brain, message).await?
+                for chat_id in quizzes.expired(duration) {
+                    if let Err(err) = finish_quiz(transport, brain, &mut quizzes, chat_id).await {
+                        skip_error(err, &format!("quiz in chat {}", chat_id))?;
+                    }
+                }
+            }
+            _ = send_timer.tick() => {
+                if let Err(err) = transport.send_queued().await {
+                    skip_error(err, "queued replies")?;
+                }
+            }
+            _ = flush_timer.tick() => brain.flush().await,
+            _ = redis_check_timer.tick() => brain.check_redis().await,
+            _ = &mut shutdown => break,
         }
     }
+
     Ok(())
 }
+
+async fn run_telegram(brain: &mut Brain, shutdown: oneshot::Receiver<()>) -> anyhow::Result<()> {
+    let config = CONFIG.get();
+    // presence of credentials of the platform is checked on load
+    let token = config
+        .telegram_bot_token
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("TELEGRAM_BOT_TOKEN not set"))?;
+    let api = Api::new(token);
+
+    // start listening before telegram is told to post updates
+    let webhook = match config.webhook_addr {
+        Some(addr) => Some(webhook::listen(addr)?),
+        None => None,
+    };
+
+    if let (Some(_), Some(ref url)) = (config.webhook_addr, &config.webhook_url) {
+        let mut req = SetWebhook::new(url.as_str());
+        if let Some(ref secret) = config.webhook_secret {
+            req.secret_token(secret.as_str());
+        }
+        api.send(req).await?;
+    }
+
+    let transport = Throttled::new(TelegramTransport::new(api, webhook));
+    handle_updates(&transport, brain, shutdown).await
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    CONFIG.init(env::args().skip(1).collect())?;
+    let config = CONFIG.get();
+
+    let con = match connect_redis(&config.redis_addr).await {
+        Ok(con) => con,
+        Err(err) => panic!("error connecting to Redis: {}", err),
+    };
+
+    let mut brain = Brain::new(1, 2).set_redis_con(con);
+
+    if let Some(addr) = config.metrics_addr {
+        // the bot works fine without metrics
+        if let Err(err) = metrics::serve(addr) {
+            log::error!("{}", err);
+        }
+    }
+    tokio::spawn(CONFIG.reload_on_hangup());
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let signal = shutdown::listen();
+
+    // runs separately from updates handling to notice signals even in the middle of long imports
+    tokio::spawn(async move {
+        let name = signal.await;
+        log::info!("{} received, finishing current work...", name);
+        let _ = shutdown_tx.send(());
+    });
+
+    let res = match config.platform {
+        Platform::Telegram => run_telegram(&mut brain, shutdown_rx).await,
+        Platform::Matrix => match (&config.matrix_homeserver, &config.matrix_access_token) {
+            (Some(homeserver), Some(access_token)) => {
+                let transport =
+                    Throttled::new(MatrixTransport::new(homeserver.clone(), access_token));
+                handle_updates(&transport, &mut brain, shutdown_rx).await
+            }
+            _ => Err(anyhow::anyhow!(
+                "MATRIX_HOMESERVER and MATRIX_ACCESS_TOKEN must be set"
+            )),
+        },
+    };
+    if let Err(ref err) = res {
+        log::error!("bot stopped because of fatal error: {}", err);
+    }
+
+    log::info!("saving all learned data...");
+    brain.flush_all().await;
+    log::info!("all data saved, bot stopped");
+
+    res
+}
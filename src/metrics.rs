@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use telegram_bot::ChatId;
+
+lazy_static::lazy_static! {
+    pub(crate) static ref METRICS: Metrics = Default::default();
+}
+
+#[derive(Default)]
+struct StorageStats {
+    count: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+/// Counters describing what the bot is doing, exposed in Prometheus text format
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) updates: AtomicU64,
+    pub(crate) update_errors: AtomicU64,
+    pub(crate) message_errors: AtomicU64,
+    pub(crate) generations: AtomicU64,
+    // generations which ran out of max_gen_retries without a result
+    pub(crate) generation_failures: AtomicU64,
+    replies: Mutex<HashMap<ChatId, u64>>,
+    storage: Mutex<HashMap<&'static str, StorageStats>>,
+    // approximate size of chains per loaded chat
+    chats: Mutex<HashMap<ChatId, usize>>,
+}
+
+pub(crate) fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    pub(crate) fn reply_sent(&self, chat_id: ChatId) {
+        *self.replies.lock().unwrap().entry(chat_id).or_default() += 1;
+    }
+
+    fn storage_op(&self, op: &'static str, elapsed: Duration, ok: bool) {
+        let mut storage = self.storage.lock().unwrap();
+        let stats = storage.entry(op).or_default();
+
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+        if !ok {
+            stats.errors += 1;
+        }
+    }
+
+    pub(crate) fn chat_loaded(&self, chat_id: ChatId, size: usize) {
+        self.chats.lock().unwrap().insert(chat_id, size);
+    }
+
+    pub(crate) fn chat_unloaded(&self, chat_id: ChatId) {
+        self.chats.lock().unwrap().remove(&chat_id);
+    }
+
+    /// Renders all the metrics in Prometheus text exposition format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            ("mimic_updates_total", "Updates received", &self.updates),
+            (
+                "mimic_update_errors_total",
+                "Errors receiving updates",
+                &self.update_errors,
+            ),
+            (
+                "mimic_message_errors_total",
+                "Messages failed to be handled",
+                &self.message_errors,
+            ),
+            (
+                "mimic_generations_total",
+                "Attempts to generate a reply",
+                &self.generations,
+            ),
+            (
+                "mimic_generation_failures_total",
+                "Generations which exhausted max_gen_retries",
+                &self.generation_failures,
+            ),
+        ];
+
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP mimic_replies_total Replies sent per chat").unwrap();
+        writeln!(out, "# TYPE mimic_replies_total counter").unwrap();
+        for (chat_id, count) in self.replies.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "mimic_replies_total{{chat_id=\"{}\"}} {}",
+                chat_id, count
+            )
+            .unwrap();
+        }
+
+        let storage = self.storage.lock().unwrap();
+
+        writeln!(
+            out,
+            "# HELP mimic_storage_operations_total Redis operations"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mimic_storage_operations_total counter").unwrap();
+        for (op, stats) in storage.iter() {
+            writeln!(
+                out,
+                "mimic_storage_operations_total{{op=\"{}\"}} {}",
+                op, stats.count
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP mimic_storage_errors_total Failed Redis operations"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mimic_storage_errors_total counter").unwrap();
+        for (op, stats) in storage.iter() {
+            writeln!(
+                out,
+                "mimic_storage_errors_total{{op=\"{}\"}} {}",
+                op, stats.errors
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            out,
+            "# HELP mimic_storage_seconds_total Time spent in Redis operations"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mimic_storage_seconds_total counter").unwrap();
+        for (op, stats) in storage.iter() {
+            writeln!(
+                out,
+                "mimic_storage_seconds_total{{op=\"{}\"}} {}",
+                op, stats.seconds
+            )
+            .unwrap();
+        }
+
+        let chats = self.chats.lock().unwrap();
+
+        writeln!(
+            out,
+            "# HELP mimic_loaded_chats Chats which data is in memory"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mimic_loaded_chats gauge").unwrap();
+        writeln!(out, "mimic_loaded_chats {}", chats.len()).unwrap();
+
+        writeln!(
+            out,
+            "# HELP mimic_chain_bytes Approximate size of chat chains"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE mimic_chain_bytes gauge").unwrap();
+        for (chat_id, size) in chats.iter() {
+            writeln!(out, "mimic_chain_bytes{{chat_id=\"{}\"}} {}", chat_id, size).unwrap();
+        }
+
+        out
+    }
+}
+
+/// Measures how long storage operation takes and whether it fails
+pub(crate) async fn timed<T, E>(
+    op: &'static str,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let res = fut.await;
+    METRICS.storage_op(op, start.elapsed(), res.is_ok());
+    res
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
+
+    Ok(Response::new(Body::from(METRICS.render())))
+}
+
+/// Starts HTTP server exposing metrics at /metrics
+pub(crate) fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|err| anyhow::anyhow!("unable to serve metrics on {}: {}", addr, err))?
+        .serve(make_svc);
+    log::info!("serving metrics on {}/metrics", addr);
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("metrics server error: {}", err);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn busy_port_is_reported() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(serve(listener.local_addr().unwrap()).is_err());
+    }
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = Metrics::default();
+
+        inc(&metrics.updates);
+        inc(&metrics.updates);
+        metrics.reply_sent(ChatId::new(-1));
+        metrics.storage_op("write", Duration::from_millis(500), false);
+        metrics.chat_loaded(ChatId::new(-1), 1024);
+
+        let text = metrics.render();
+
+        for line in &[
+            "# TYPE mimic_updates_total counter",
+            "mimic_updates_total 2",
+            "mimic_replies_total{chat_id=\"-1\"} 1",
+            "mimic_storage_operations_total{op=\"write\"} 1",
+            "mimic_storage_errors_total{op=\"write\"} 1",
+            "mimic_storage_seconds_total{op=\"write\"} 0.5",
+            "mimic_loaded_chats 1",
+            "mimic_chain_bytes{chat_id=\"-1\"} 1024",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{} not found in:\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
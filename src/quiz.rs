@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rand::{seq::SliceRandom, Rng};
+use telegram_bot::ChatId;
+
+use super::brain::UserName;
+use super::transport::IncomingMessage;
+
+/// Outcome of a vote
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum VoteResult {
+    Counted,
+    AlreadyVoted,
+    /// the quiz is over or the option doesn't exist
+    Rejected,
+}
+
+/// "Who said it?" round: a generated sentence and users who might have said it
+pub(crate) struct Quiz {
+    // command which started the quiz, results are posted in reply to it
+    pub(crate) question: IncomingMessage,
+    pub(crate) answer: UserName,
+    pub(crate) options: Vec<UserName>,
+    votes: HashMap<UserName, usize>,
+    started: Instant,
+}
+
+impl Quiz {
+    /// Mixes the answer with other users and keeps at most `size` options
+    pub(crate) fn new(
+        question: IncomingMessage,
+        answer: UserName,
+        others: Vec<UserName>,
+        size: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut options = others
+            .into_iter()
+            .filter(|name| *name != answer)
+            .collect::<Vec<UserName>>();
+        options.shuffle(rng);
+        options.truncate(size.saturating_sub(1));
+        options.push(answer.clone());
+        options.shuffle(rng);
+
+        Quiz {
+            question,
+            answer,
+            options,
+            votes: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn id(&self) -> i64 {
+        self.question.id.into()
+    }
+
+    /// Buttons to vote with, each one is a label and data sent back with the vote
+    pub(crate) fn choices(&self) -> Vec<(String, String)> {
+        self.options
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                (
+                    format!("{}. {}", i + 1, name),
+                    format!("quiz:{}:{}", self.id(), i + 1),
+                )
+            })
+            .collect()
+    }
+
+    fn vote(&mut self, voter: UserName, choice: usize) -> VoteResult {
+        if choice == 0 || choice > self.options.len() {
+            return VoteResult::Rejected;
+        }
+
+        if self.votes.contains_key(&voter) {
+            return VoteResult::AlreadyVoted;
+        }
+
+        self.votes.insert(voter, choice - 1);
+        VoteResult::Counted
+    }
+
+    /// Returns voters who guessed right
+    pub(crate) fn winners(&self) -> Vec<UserName> {
+        let mut winners = self
+            .votes
+            .iter()
+            .filter(|(_, choice)| self.options[**choice] == self.answer)
+            .map(|(voter, _)| voter.clone())
+            .collect::<Vec<UserName>>();
+        winners.sort_by(|a, b| a.0.cmp(&b.0));
+        winners
+    }
+}
+
+/// Parses data of a quiz button into quiz id and chosen option
+pub(crate) fn parse_vote(data: &str) -> Option<(i64, usize)> {
+    let mut parts = data.strip_prefix("quiz:")?.splitn(2, ':');
+    let id = parts.next()?.parse().ok()?;
+    let choice = parts.next()?.parse().ok()?;
+    Some((id, choice))
+}
+
+/// Quizzes running in chats, one per chat at most
+#[derive(Default)]
+pub(crate) struct Quizzes {
+    chats: HashMap<ChatId, Quiz>,
+}
+
+impl Quizzes {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn is_running(&self, chat_id: ChatId) -> bool {
+        self.chats.contains_key(&chat_id)
+    }
+
+    pub(crate) fn start(&mut self, quiz: Quiz) -> &Quiz {
+        let chat_id = quiz.question.chat_id;
+        self.chats.insert(chat_id, quiz);
+        &self.chats[&chat_id]
+    }
+
+    /// Counts the vote, `quiz_id` is checked if given so buttons of finished quizzes don't work
+    pub(crate) fn vote(
+        &mut self,
+        chat_id: ChatId,
+        quiz_id: Option<i64>,
+        voter: UserName,
+        choice: usize,
+    ) -> VoteResult {
+        match self.chats.get_mut(&chat_id) {
+            Some(quiz) if quiz_id.is_none() || quiz_id == Some(quiz.id()) => {
+                quiz.vote(voter, choice)
+            }
+            _ => VoteResult::Rejected,
+        }
+    }
+
+    pub(crate) fn finish(&mut self, chat_id: ChatId) -> Option<Quiz> {
+        self.chats.remove(&chat_id)
+    }
+
+    /// Returns chats where quizzes have been running for longer than `duration`
+    pub(crate) fn expired(&self, duration: Duration) -> Vec<ChatId> {
+        self.chats
+            .iter()
+            .filter(|(_, quiz)| quiz.started.elapsed() >= duration)
+            .map(|(chat_id, _)| *chat_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{fake, Content};
+
+    fn quiz(others: &[&str]) -> Quiz {
+        let question = fake::message(-1, 10, "Carol", Content::Text("/quiz".to_owned()));
+        let others = others.iter().map(|&name| UserName::from(name)).collect();
+        Quiz::new(
+            question,
+            UserName::from("Alice"),
+            others,
+            3,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    #[test]
+    fn options_include_the_answer() {
+        let quiz = quiz(&["Alice", "Bob", "Carol", "Dave", "Eve"]);
+
+        assert_eq!(quiz.options.len(), 3);
+        assert_eq!(
+            quiz.options
+                .iter()
+                .filter(|name| **name == quiz.answer)
+                .count(),
+            1
+        );
+        assert_eq!(quiz.choices()[0].1, "quiz:10:1");
+    }
+
+    #[test]
+    fn one_vote_per_voter() {
+        let mut quizzes = Quizzes::new();
+        let quiz = quizzes.start(quiz(&["Bob"]));
+        let right = quiz.options.iter().position(|n| *n == quiz.answer).unwrap() + 1;
+        let wrong = 3 - right;
+        let chat_id = ChatId::new(-1);
+
+        let vote = |quizzes: &mut Quizzes, id, voter, choice| {
+            quizzes.vote(chat_id, id, UserName::from(voter), choice)
+        };
+
+        assert_eq!(
+            vote(&mut quizzes, Some(10), "Bob", right),
+            VoteResult::Counted
+        );
+        assert_eq!(
+            vote(&mut quizzes, None, "Bob", wrong),
+            VoteResult::AlreadyVoted
+        );
+        assert_eq!(vote(&mut quizzes, None, "Dave", wrong), VoteResult::Counted);
+        assert_eq!(
+            vote(&mut quizzes, Some(9), "Eve", right),
+            VoteResult::Rejected
+        );
+        assert_eq!(vote(&mut quizzes, None, "Eve", 3), VoteResult::Rejected);
+
+        let quiz = quizzes.finish(chat_id).unwrap();
+        assert_eq!(quiz.winners(), vec![UserName::from("Bob")]);
+        assert!(!quizzes.is_running(chat_id));
+    }
+
+    #[test]
+    fn vote_data() {
+        assert_eq!(parse_vote("quiz:-5:2"), Some((-5, 2)));
+        assert_eq!(parse_vote("quiz:5"), None);
+        assert_eq!(parse_vote("other:5:2"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+
+use serde::Serialize;
+use telegram_bot::{
+    ChatRef, HttpRequest, JsonIdResponse, JsonRequestType, JsonTrueToUnitResponse, MessageId,
+    MessageOrChannelPost, Request, RequestType, RequestUrl, ToChatRef, ToMessageId,
+};
+
+/// Use this method to send stickers, telegram-bot doesn't provide it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub(crate) struct SendSticker<'s> {
+    chat_id: ChatRef,
+    sticker: Cow<'s, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to_message_id: Option<MessageId>,
+}
+
+impl<'s> Request for SendSticker<'s> {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<MessageOrChannelPost>;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot_raw::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("sendSticker"), self)
+    }
+}
+
+impl<'s> SendSticker<'s> {
+    pub(crate) fn new<C, T>(chat: C, file_id: T) -> Self
+    where
+        C: ToChatRef,
+        T: Into<Cow<'s, str>>,
+    {
+        SendSticker {
+            chat_id: chat.to_chat_ref(),
+            sticker: file_id.into(),
+            reply_to_message_id: None,
+        }
+    }
+
+    pub(crate) fn reply_to<R>(&mut self, to: R) -> &mut Self
+    where
+        R: ToMessageId,
+    {
+        self.reply_to_message_id = Some(to.to_message_id());
+        self
+    }
+}
+
+/// Use this method to receive updates via webhook instead of long polling
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub(crate) struct SetWebhook<'s> {
+    url: Cow<'s, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_token: Option<Cow<'s, str>>,
+}
+
+impl<'s> Request for SetWebhook<'s> {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot_raw::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("setWebhook"), self)
+    }
+}
+
+impl<'s> SetWebhook<'s> {
+    pub(crate) fn new<T>(url: T) -> Self
+    where
+        T: Into<Cow<'s, str>>,
+    {
+        SetWebhook {
+            url: url.into(),
+            secret_token: None,
+        }
+    }
+
+    pub(crate) fn secret_token<T>(&mut self, token: T) -> &mut Self
+    where
+        T: Into<Cow<'s, str>>,
+    {
+        self.secret_token = Some(token.into());
+        self
+    }
+}
+
+/// Use this method to answer a button press, telegram-bot only answers
+/// queries it has parsed itself
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub(crate) struct AnswerCallbackQuery<'s> {
+    callback_query_id: Cow<'s, str>,
+    text: Cow<'s, str>,
+}
+
+impl<'s> Request for AnswerCallbackQuery<'s> {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, telegram_bot_raw::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("answerCallbackQuery"), self)
+    }
+}
+
+impl<'s> AnswerCallbackQuery<'s> {
+    pub(crate) fn new<Q, T>(query_id: Q, text: T) -> Self
+    where
+        Q: Into<Cow<'s, str>>,
+        T: Into<Cow<'s, str>>,
+    {
+        AnswerCallbackQuery {
+            callback_query_id: query_id.into(),
+            text: text.into(),
+        }
+    }
+}
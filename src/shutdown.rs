@@ -0,0 +1,29 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true once the bot is asked to stop, long running jobs
+/// should save what they've done so far and return as soon as possible
+pub(crate) fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Installs SIGINT and SIGTERM handlers, returned future resolves with the name
+/// of the first signal received, by that time shutdown is marked as requested
+pub(crate) fn listen() -> impl Future<Output = &'static str> {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("unable to install SIGINT handler");
+    let mut terminate = signal(SignalKind::terminate()).expect("unable to install SIGTERM handler");
+
+    async move {
+        let name = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        };
+
+        REQUESTED.store(true, Ordering::SeqCst);
+        name
+    }
+}
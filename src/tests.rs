@@ -0,0 +1,584 @@
+//! End to end tests running commands and chat messages through the fake transport
+
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Once;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+use telegram_bot::ChatId;
+use tokio::sync::oneshot;
+
+use super::brain::{Brain, UserName};
+use super::handle_updates;
+use super::transport::fake::{FakeTransport, Sent};
+
+const CHAT_ID: i64 = -100;
+
+const HISTORY: &str = r#"{
+    "messages": [
+        {"id": 1, "type": "message", "date": "2020-11-01T10:00:00", "from": "Alice", "text": "I like green apples"},
+        {"id": 2, "type": "message", "date": "2020-11-01T10:01:00", "from": "Alice", "text": "green tea is nice in the morning"},
+        {"id": 3, "type": "message", "date": "2020-11-01T10:02:00", "from": "Alice", "text": "the morning sun makes me happy"},
+        {"id": 4, "type": "message", "date": "2020-11-01T10:03:00", "from": "Alice", "text": "apples and tea make a good breakfast"},
+        {"id": 5, "type": "message", "date": "2020-11-01T10:04:00", "from": "Bob", "text": "hello there"}
+    ]
+}"#;
+
+static INIT: Once = Once::new();
+
+// config is read once, so all tests share the same settings
+fn init() {
+    INIT.call_once(|| {
+        env::set_var("REPLY_PROB_DEFAULT", "1");
+        env::set_var("KNOWN_WORD_REPLY_PROB", "1");
+        env::set_var("EDIT_WINDOW", "0");
+    });
+}
+
+// serves chat history export for /learn to download
+fn serve_history() -> SocketAddr {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            Ok::<_, Infallible>(Response::new(Body::from(HISTORY)))
+        }))
+    });
+
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    addr
+}
+
+// handles all the queued events and returns once they are over
+async fn try_run(transport: &FakeTransport, brain: &mut Brain) -> anyhow::Result<()> {
+    let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+    handle_updates(transport, brain, shutdown_rx).await
+}
+
+async fn run(transport: &FakeTransport, brain: &mut Brain) {
+    try_run(transport, brain)
+        .await
+        .expect("updates must be handled without errors");
+}
+
+async fn learned_brain(transport: &FakeTransport) -> Brain {
+    let mut brain = Brain::new(1, 2);
+    let addr = serve_history();
+
+    transport.push_text(
+        CHAT_ID,
+        1,
+        "Carol",
+        &format!("/learn http://{}/history.json Alice", addr),
+    );
+    run(transport, &mut brain).await;
+    transport.take_sent();
+
+    brain
+}
+
+#[tokio::test]
+async fn learn_downloads_history_of_one_user() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+    let addr = serve_history();
+
+    transport.push_text(
+        CHAT_ID,
+        1,
+        "Carol",
+        &format!("/learn http://{}/history.json Alice", addr),
+    );
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Downloading history data",
+            "Download completed",
+            "Learning...",
+            "Done learning, 4 messages proccessed!",
+        ]
+    );
+
+    let chat_id = ChatId::new(CHAT_ID);
+    assert!(brain.is_known_user(chat_id, &UserName::from("Alice")));
+    assert!(!brain.is_known_user(chat_id, &UserName::from("Bob")));
+}
+
+#[tokio::test]
+async fn learn_requires_user_name() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+
+    transport.push_text(CHAT_ID, 1, "Carol", "/learn http://localhost/history.json");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(transport.take_texts(), vec!["User name must be provided"]);
+}
+
+#[tokio::test]
+async fn say_replies_as_learned_user() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    transport.push_text(CHAT_ID, 2, "Carol", "/say 1");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 1);
+    assert!(
+        texts[0].starts_with("Alice: "),
+        "unexpected reply: {}",
+        texts[0]
+    );
+}
+
+#[tokio::test]
+async fn say_with_seed_repeats_itself() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    for id in 2..6 {
+        transport.push_text(CHAT_ID, id, "Carol", "/say 1 --seed 42");
+    }
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 4);
+    assert!(texts.iter().all(|text| *text == texts[0]), "{:?}", texts);
+
+    transport.push_text(CHAT_ID, 6, "Carol", "/say 1 --seed many");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec!["Wrong syntax, use '/say order (from 1 to 2) [--seed number]'"]
+    );
+}
+
+#[tokio::test]
+async fn say_shows_seed_to_repeat_reply() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    transport.push_text(CHAT_ID, 2, "Carol", "/say 1");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 1);
+    let seed = texts[0]
+        .strip_suffix(')')
+        .and_then(|text| text.rsplit_once("(seed "))
+        .map(|(_, seed)| seed.to_owned())
+        .unwrap_or_else(|| panic!("no seed in reply: {}", texts[0]));
+
+    transport.push_text(CHAT_ID, 3, "Carol", &format!("/say 1 --seed {}", seed));
+    run(&transport, &mut brain).await;
+
+    assert_eq!(transport.take_texts(), texts);
+}
+
+#[tokio::test]
+async fn say_is_silent_without_learned_data() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+
+    transport.push_text(CHAT_ID, 1, "Carol", "/say 1");
+    run(&transport, &mut brain).await;
+
+    assert!(transport.take_sent().is_empty());
+}
+
+#[tokio::test]
+async fn passive_reply_to_known_words() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    transport.make_admin("Carol");
+
+    // the history is tiny, replies can't help repeating 5 of its words in a row
+    transport.push_text(CHAT_ID, 2, "Carol", "/overlap 8");
+    transport.push_text(CHAT_ID, 3, "Carol", "do you drink tea?");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 2);
+    assert!(
+        texts[1].starts_with("Alice: ") && texts[1].to_lowercase().contains("tea"),
+        "unexpected reply: {}",
+        texts[1]
+    );
+}
+
+#[tokio::test]
+async fn stats_describe_learned_users() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    transport.push_text(CHAT_ID, 2, "Carol", "/stats");
+    transport.push_text(CHAT_ID, 3, "Carol", "/stats @alice");
+    transport.push_text(CHAT_ID, 4, "Carol", "/stats @Bob");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 3);
+
+    assert!(
+        texts[0].starts_with("1 users, 4 messages, "),
+        "unexpected stats: {}",
+        texts[0]
+    );
+    assert!(
+        texts[1].starts_with("Alice: 4 messages, 19 words, transitions: "),
+        "unexpected stats: {}",
+        texts[1]
+    );
+    assert!(texts[1].contains("distinctive words: "));
+    assert!(texts[1].contains("apples"));
+    assert!(!texts[1].contains(" the,"));
+    assert_eq!(texts[2], "Nothing is known about Bob");
+}
+
+#[tokio::test]
+async fn quiz_reveals_answer_and_keeps_score() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    let addr = serve_history();
+
+    transport.push_text(
+        CHAT_ID,
+        2,
+        "Carol",
+        &format!("/learn http://{}/history.json Bob", addr),
+    );
+    transport.push_text(CHAT_ID, 3, "Carol", "/quiz");
+    // buttons carry id of the message which started the quiz and the option number
+    transport.push_vote(CHAT_ID, "1", "Dave", "quiz:3:1");
+    transport.push_vote(CHAT_ID, "2", "Eve", "quiz:3:2");
+    transport.push_text(CHAT_ID, 4, "Dave", "/guess 2");
+    transport.push_text(CHAT_ID, 5, "Carol", "/reveal");
+    transport.push_text(CHAT_ID, 6, "Carol", "/leaderboard");
+    run(&transport, &mut brain).await;
+
+    // learning replies go first
+    let mut sent = transport.take_sent().split_off(4);
+    let choices = match sent.remove(0) {
+        Sent::Choices { text, choices, .. } => {
+            assert!(
+                text.starts_with("Who said it?"),
+                "unexpected quiz: {}",
+                text
+            );
+            choices
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert_eq!(choices.len(), 2);
+
+    let texts = sent
+        .into_iter()
+        .filter_map(|sent| match sent {
+            Sent::Text { text, .. } | Sent::VoteAnswer { text, .. } => Some(text),
+            _ => None,
+        })
+        .collect::<Vec<String>>();
+    assert_eq!(texts.len(), 5);
+    assert_eq!(
+        texts[..3],
+        ["Vote counted", "Vote counted", "You have already voted"]
+    );
+
+    let (answer, winner) = if texts[3].starts_with("It was Alice!") {
+        (
+            "Alice",
+            if choices[0].0 == "1. Alice" {
+                "Dave"
+            } else {
+                "Eve"
+            },
+        )
+    } else {
+        (
+            "Bob",
+            if choices[0].0 == "1. Bob" {
+                "Dave"
+            } else {
+                "Eve"
+            },
+        )
+    };
+    assert_eq!(
+        texts[3],
+        format!("It was {}! Guessed right: {}", answer, winner)
+    );
+    assert_eq!(texts[4], format!("1. {}: 1", winner));
+}
+
+#[tokio::test]
+async fn quiz_needs_two_users() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    transport.push_text(CHAT_ID, 2, "Carol", "/quiz");
+    transport.push_text(CHAT_ID, 3, "Carol", "/guess 1");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Quiz needs at least two learned users",
+            "No quiz is running, start one with /quiz",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn whosaid_ranks_likely_authors() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    let addr = serve_history();
+
+    transport.push_text(
+        CHAT_ID,
+        2,
+        "Carol",
+        &format!("/learn http://{}/history.json Bob", addr),
+    );
+    run(&transport, &mut brain).await;
+    transport.take_sent();
+
+    transport.push_text(CHAT_ID, 3, "Carol", "/whosaid green tea in the morning");
+    transport.push_text(CHAT_ID, 4, "Carol", "/whosaid hello there");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert_eq!(texts.len(), 2);
+
+    for (text, first) in texts.iter().zip(&["1. Alice: ", "1. Bob: "]) {
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2, "unexpected reply: {}", text);
+        assert!(lines[0].starts_with(first), "unexpected reply: {}", text);
+    }
+}
+
+#[tokio::test]
+async fn only_known_users_are_learned_from_chat() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    let chat_id = ChatId::new(CHAT_ID);
+
+    transport.push_text(CHAT_ID, 2, "Alice", "bananas are yellow");
+    transport.push_text(CHAT_ID, 3, "Alice", "lemons are sour");
+    transport.push_text(CHAT_ID, 4, "Dave", "oranges are orange");
+    run(&transport, &mut brain).await;
+
+    assert!(brain
+        .gen_from_message(chat_id, "bananas", 1, &mut rand::thread_rng())
+        .is_some());
+    assert!(!brain.is_known_user(chat_id, &UserName::from("Dave")));
+}
+
+#[tokio::test]
+async fn chat_errors_dont_stop_the_bot() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    let chat_id = ChatId::new(CHAT_ID);
+
+    transport.fail_sends("Forbidden: bot was kicked from the group chat");
+    transport.push_text(CHAT_ID, 2, "Carol", "/say 1");
+    transport.push_text(CHAT_ID, 3, "Alice", "bananas are yellow");
+    transport.push_text(CHAT_ID, 4, "Alice", "lemons are sour");
+    run(&transport, &mut brain).await;
+
+    // messages after the failed one are still learned
+    assert!(brain
+        .gen_from_message(chat_id, "bananas", 1, &mut rand::thread_rng())
+        .is_some());
+}
+
+#[tokio::test]
+async fn fatal_errors_stop_the_bot() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+
+    transport.fail_sends("Unauthorized");
+    transport.push_text(CHAT_ID, 2, "Carol", "/say 1");
+
+    assert!(try_run(&transport, &mut brain).await.is_err());
+}
+
+#[tokio::test]
+async fn forwards_policy_is_changed_by_admins() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+    transport.make_admin("Carol");
+
+    transport.push_text(CHAT_ID, 1, "Dave", "/forwards skip");
+    transport.push_text(CHAT_ID, 2, "Carol", "/forwards skip");
+    transport.push_text(CHAT_ID, 3, "Dave", "/forwards");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Only chat admins can change chat settings",
+            "Forwarded messages policy set to: skip",
+            "Forwarded messages are credited to: skip, use '/forwards skip|sender|author' to change it",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn overlap_is_changed_by_admins() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+    transport.make_admin("Carol");
+
+    transport.push_text(CHAT_ID, 1, "Dave", "/overlap 6");
+    transport.push_text(CHAT_ID, 2, "Carol", "/overlap 6");
+    transport.push_text(CHAT_ID, 3, "Dave", "/overlap");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Only chat admins can change chat settings",
+            "Replies with 6 words in a row from learned messages are dropped now",
+            "Replies with 6 words in a row from learned messages are dropped, use '/overlap number' to change it",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn weights_are_set_per_chat() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+    transport.make_admin("Carol");
+
+    transport.push_text(CHAT_ID, 0, "Dave", "/weights novelty=2");
+    transport.push_text(CHAT_ID, 1, "Carol", "/weights novelty=2 seed=0");
+    transport.push_text(CHAT_ID, 2, "Carol", "/weights color=1");
+    transport.push_text(CHAT_ID, 3, "Carol", "/weights");
+    transport.push_text(CHAT_ID - 1, 4, "Carol", "/weights");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Only chat admins can change chat settings",
+            "Replies are ranked with weights length=1 likelihood=1 novelty=2 seed=0 ending=1 now",
+            "unknown weight 'color'",
+            "Replies are ranked with weights length=1 likelihood=1 novelty=2 seed=0 ending=1, use '/weights name=value' to change them",
+            "Replies are ranked with weights length=1 likelihood=1 novelty=1 seed=1 ending=1, use '/weights name=value' to change them",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn blocklist_is_changed_by_admins() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = Brain::new(1, 2);
+    transport.make_admin("Dave");
+
+    transport.push_text(CHAT_ID, 1, "Carol", "/block darn");
+    transport.push_text(CHAT_ID, 2, "Dave", "/block Darn");
+    transport.push_text(CHAT_ID, 3, "Dave", "/block darn");
+    transport.push_text(CHAT_ID, 4, "Dave", "/block /fr[ie]+k/");
+    transport.push_text(CHAT_ID, 5, "Carol", "/block");
+    transport.push_text(CHAT_ID, 6, "Dave", "/unblock darn");
+    transport.push_text(CHAT_ID, 7, "Dave", "/unblock darn");
+    transport.push_text(CHAT_ID - 1, 8, "Carol", "/block");
+    run(&transport, &mut brain).await;
+
+    assert_eq!(
+        transport.take_texts(),
+        vec![
+            "Only chat admins can change the blocklist",
+            "'darn' is blocked now",
+            "'darn' is blocked already",
+            "'/fr[ie]+k/' is blocked now",
+            "Replies never contain: darn, /fr[ie]+k/, use '/unblock word' to allow it again",
+            "'darn' isn't blocked anymore",
+            "'darn' isn't blocked",
+            "Nothing is blocked, use '/block word' or '/block /regex/' to block it",
+        ]
+    );
+
+    transport.push_text(CHAT_ID, 9, "Dave", "/block /fr[ie+k/");
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert!(texts[0].starts_with("invalid regex"), "{:?}", texts);
+}
+
+#[tokio::test]
+async fn blocked_words_are_never_said() {
+    init();
+
+    let transport = FakeTransport::new();
+    let mut brain = learned_brain(&transport).await;
+    transport.make_admin("Carol");
+
+    transport.push_text(CHAT_ID, 2, "Carol", "/block green");
+    run(&transport, &mut brain).await;
+    transport.take_sent();
+
+    for seed in 0..20 {
+        transport.push_text(
+            CHAT_ID,
+            3 + seed,
+            "Carol",
+            &format!("/say 1 --seed {}", seed),
+        );
+    }
+    run(&transport, &mut brain).await;
+
+    let texts = transport.take_texts();
+    assert!(!texts.is_empty());
+    assert!(
+        texts
+            .iter()
+            .all(|text| !text.to_lowercase().contains("green")),
+        "{:?}",
+        texts
+    );
+}
@@ -0,0 +1,112 @@
+#[cfg(test)]
+pub(crate) mod fake;
+pub(crate) mod matrix;
+pub(crate) mod telegram;
+pub(crate) mod throttled;
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use futures::stream::LocalBoxStream;
+use telegram_bot::{ChatId, MessageId};
+
+use super::brain::UserName;
+
+/// What the message brings to learn from
+#[derive(Clone, Debug)]
+pub(crate) enum Content {
+    /// ordinary text message, might be a command
+    Text(String),
+    /// text attached to a photo, video or document
+    Caption(String),
+    Sticker {
+        file_id: String,
+        emoji: Option<String>,
+    },
+}
+
+/// Chat message as the bot sees it, whatever platform it came from
+#[derive(Clone, Debug)]
+pub(crate) struct IncomingMessage {
+    pub(crate) id: MessageId,
+    pub(crate) chat_id: ChatId,
+    // unix time the message was sent at
+    pub(crate) date: i64,
+    pub(crate) sender: UserName,
+    // platform id of the sender, names aren't unique
+    pub(crate) sender_id: String,
+    // original author if the message is forwarded
+    pub(crate) forwarded_from: Option<UserName>,
+    pub(crate) content: Content,
+}
+
+impl IncomingMessage {
+    /// Returns text of the message or its caption
+    pub(crate) fn text(&self) -> Option<&str> {
+        match self.content {
+            Content::Text(ref text) | Content::Caption(ref text) => Some(text),
+            Content::Sticker { .. } => None,
+        }
+    }
+}
+
+/// Button under a bot message pressed by a chat member
+#[derive(Clone, Debug)]
+pub(crate) struct Vote {
+    // platform id of the vote, used to acknowledge it
+    pub(crate) id: String,
+    pub(crate) chat_id: ChatId,
+    pub(crate) voter: UserName,
+    // data attached to the pressed button
+    pub(crate) data: String,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Event {
+    Message(IncomingMessage),
+    Edit(IncomingMessage),
+    Vote(Vote),
+}
+
+/// Platform refused to send a message because of flood control and asks to repeat it later
+#[derive(Debug)]
+pub(crate) struct RetryAfter(pub(crate) Duration);
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "too many requests, retry after {:?}", self.0)
+    }
+}
+
+impl Error for RetryAfter {}
+
+/// Connects the bot to a chat platform, receives messages and sends replies
+pub(crate) trait Transport {
+    /// Stream of events the bot has to handle, the stream ends when the platform disconnects
+    fn updates(&self) -> LocalBoxStream<'static, anyhow::Result<Event>>;
+
+    async fn reply_text(&self, to: &IncomingMessage, text: &str) -> anyhow::Result<()>;
+
+    async fn reply_sticker(&self, to: &IncomingMessage, file_id: &str) -> anyhow::Result<()>;
+
+    /// Replies with buttons to vote with, `choices` are button labels and data sent back
+    /// with votes, platforms without buttons just list the labels
+    async fn reply_choices(
+        &self,
+        to: &IncomingMessage,
+        text: &str,
+        choices: &[(String, String)],
+    ) -> anyhow::Result<()>;
+
+    /// Lets the voter know the vote is handled
+    async fn answer_vote(&self, vote: &Vote, text: &str) -> anyhow::Result<()>;
+
+    /// Checks whether the sender of the message administers the chat it was sent to
+    async fn is_admin(&self, message: &IncomingMessage) -> anyhow::Result<bool>;
+
+    /// Sends replies put off because of rate limits, called periodically
+    async fn send_queued(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
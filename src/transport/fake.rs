@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use telegram_bot::{ChatId, MessageId};
+
+use super::{Content, Event, IncomingMessage, RetryAfter, Transport, Vote};
+use crate::brain::UserName;
+
+/// Reply the bot sent through the fake transport
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Sent {
+    Text {
+        chat_id: ChatId,
+        reply_to: MessageId,
+        text: String,
+    },
+    Sticker {
+        chat_id: ChatId,
+        reply_to: MessageId,
+        file_id: String,
+    },
+    Choices {
+        chat_id: ChatId,
+        reply_to: MessageId,
+        text: String,
+        choices: Vec<(String, String)>,
+    },
+    VoteAnswer {
+        vote_id: String,
+        text: String,
+    },
+}
+
+/// In-memory transport, replays queued events and records replies
+#[derive(Default)]
+pub(crate) struct FakeTransport {
+    events: Mutex<Vec<Event>>,
+    sent: Mutex<Vec<Sent>>,
+    // error all the replies fail with
+    failure: Mutex<Option<String>>,
+    // chats the next reply to fails because of flood control, with the delay asked for
+    floods: Mutex<HashMap<ChatId, Duration>>,
+    // senders administering every chat
+    admins: Mutex<HashSet<String>>,
+}
+
+impl FakeTransport {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    /// Queues text message as if it was just sent
+    pub(crate) fn push_text(&self, chat_id: i64, id: i64, sender: &str, text: &str) {
+        self.push(Event::Message(message(
+            chat_id,
+            id,
+            sender,
+            Content::Text(text.to_owned()),
+        )));
+    }
+
+    /// Queues a button press
+    pub(crate) fn push_vote(&self, chat_id: i64, id: &str, voter: &str, data: &str) {
+        self.push(Event::Vote(Vote {
+            id: id.to_owned(),
+            chat_id: ChatId::new(chat_id),
+            voter: UserName::from(voter),
+            data: data.to_owned(),
+        }));
+    }
+
+    pub(crate) fn push(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Makes all the following replies fail with the given error message
+    pub(crate) fn fail_sends(&self, msg: &str) {
+        *self.failure.lock().unwrap() = Some(msg.to_owned());
+    }
+
+    /// Makes the sender an admin of all the chats
+    pub(crate) fn make_admin(&self, sender: &str) {
+        self.admins.lock().unwrap().insert(sender.to_owned());
+    }
+
+    /// Makes the next reply to the chat fail as if flood control rejected it
+    pub(crate) fn flood_chat(&self, chat_id: i64, delay: Duration) {
+        self.floods
+            .lock()
+            .unwrap()
+            .insert(ChatId::new(chat_id), delay);
+    }
+
+    fn check_failure(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        if let Some(delay) = self.floods.lock().unwrap().remove(&chat_id) {
+            return Err(RetryAfter(delay).into());
+        }
+
+        match *self.failure.lock().unwrap() {
+            Some(ref msg) => Err(anyhow::anyhow!("{}", msg)),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes replies sent since the last call
+    pub(crate) fn take_sent(&self) -> Vec<Sent> {
+        std::mem::take(&mut *self.sent.lock().unwrap())
+    }
+
+    /// Takes texts of replies sent since the last call
+    pub(crate) fn take_texts(&self) -> Vec<String> {
+        self.take_sent()
+            .into_iter()
+            .filter_map(|sent| match sent {
+                Sent::Text { text, .. }
+                | Sent::Choices { text, .. }
+                | Sent::VoteAnswer { text, .. } => Some(text),
+                Sent::Sticker { .. } => None,
+            })
+            .collect()
+    }
+}
+
+/// Builds message as if it was just sent
+pub(crate) fn message(chat_id: i64, id: i64, sender: &str, content: Content) -> IncomingMessage {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    IncomingMessage {
+        id: MessageId::new(id),
+        chat_id: ChatId::new(chat_id),
+        date: now as i64,
+        sender: UserName::from(sender),
+        sender_id: sender.to_owned(),
+        forwarded_from: None,
+        content,
+    }
+}
+
+impl Transport for FakeTransport {
+    fn updates(&self) -> LocalBoxStream<'static, anyhow::Result<Event>> {
+        let events = std::mem::take(&mut *self.events.lock().unwrap());
+        stream::iter(events.into_iter().map(Ok)).boxed_local()
+    }
+
+    async fn reply_text(&self, to: &IncomingMessage, text: &str) -> anyhow::Result<()> {
+        self.check_failure(to.chat_id)?;
+        self.sent.lock().unwrap().push(Sent::Text {
+            chat_id: to.chat_id,
+            reply_to: to.id,
+            text: text.to_owned(),
+        });
+        Ok(())
+    }
+
+    async fn reply_sticker(&self, to: &IncomingMessage, file_id: &str) -> anyhow::Result<()> {
+        self.check_failure(to.chat_id)?;
+        self.sent.lock().unwrap().push(Sent::Sticker {
+            chat_id: to.chat_id,
+            reply_to: to.id,
+            file_id: file_id.to_owned(),
+        });
+        Ok(())
+    }
+
+    async fn reply_choices(
+        &self,
+        to: &IncomingMessage,
+        text: &str,
+        choices: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        self.check_failure(to.chat_id)?;
+        self.sent.lock().unwrap().push(Sent::Choices {
+            chat_id: to.chat_id,
+            reply_to: to.id,
+            text: text.to_owned(),
+            choices: choices.to_vec(),
+        });
+        Ok(())
+    }
+
+    async fn answer_vote(&self, vote: &Vote, text: &str) -> anyhow::Result<()> {
+        self.check_failure(vote.chat_id)?;
+        self.sent.lock().unwrap().push(Sent::VoteAnswer {
+            vote_id: vote.id.clone(),
+            text: text.to_owned(),
+        });
+        Ok(())
+    }
+
+    async fn is_admin(&self, message: &IncomingMessage) -> anyhow::Result<bool> {
+        Ok(self.admins.lock().unwrap().contains(&message.sender_id))
+    }
+}
@@ -0,0 +1,637 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use telegram_bot::{ChatId, MessageId};
+
+use super::{Content, Event, IncomingMessage, RetryAfter, Transport, Vote};
+use crate::brain::UserName;
+
+// how long the homeserver may hold sync request waiting for new events
+const SYNC_TIMEOUT_MS: &str = "30000";
+
+#[derive(Deserialize, Debug)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Rooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct JoinedRoom {
+    #[serde(default)]
+    state: Events,
+    #[serde(default)]
+    timeline: Events,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Events {
+    #[serde(default)]
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    event_id: String,
+    sender: String,
+    #[serde(default)]
+    origin_server_ts: i64,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct WhoAmI {
+    user_id: String,
+}
+
+// defaults are the ones the spec defines for rooms which don't set levels explicitly
+fn default_state_level() -> i64 {
+    50
+}
+
+#[derive(Deserialize, Debug)]
+struct PowerLevels {
+    #[serde(default)]
+    users: HashMap<String, i64>,
+    #[serde(default)]
+    users_default: i64,
+    // level needed to change room settings, users having it are considered admins
+    #[serde(default = "default_state_level")]
+    state_default: i64,
+}
+
+/// Maps matrix string ids to numbers the bot core identifies chats and messages with,
+/// FNV-1a is used as it is stable across restarts unlike the std hasher
+fn numeric_id(id: &str) -> i64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in id.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash as i64
+}
+
+fn chat_id(room_id: &str) -> ChatId {
+    ChatId::new(numeric_id(room_id))
+}
+
+fn message_id(event_id: &str) -> MessageId {
+    MessageId::new(numeric_id(event_id))
+}
+
+// "@alice:example.org" -> "alice"
+fn localpart(user_id: &str) -> &str {
+    let user_id = user_id.trim_start_matches('@');
+    user_id.split(':').next().unwrap_or(user_id)
+}
+
+// replies quote the original message in lines starting with "> ", they aren't words of the sender
+fn strip_reply_fallback(body: &str) -> String {
+    body.lines()
+        .skip_while(|line| line.starts_with('>'))
+        .skip_while(|line| line.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn message_content(content: &Value) -> Option<Content> {
+    let body = content["body"].as_str()?;
+
+    match content["msgtype"].as_str()? {
+        "m.text" | "m.emote" => {
+            let body = if content["m.relates_to"]["m.in_reply_to"].is_object() {
+                strip_reply_fallback(body)
+            } else {
+                body.to_owned()
+            };
+            Some(Content::Text(body))
+        }
+        // body of a media message is its caption only if file name is given separately
+        "m.image" | "m.video" | "m.file" => match content["filename"].as_str() {
+            Some(filename) if filename != body => Some(Content::Caption(body.to_owned())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Converts events of a joined room, display names of the room members are collected along the way
+fn room_events(
+    room_id: &str,
+    room: &JoinedRoom,
+    own_id: &str,
+    names: &mut HashMap<String, String>,
+) -> Vec<Event> {
+    let mut res = Vec::new();
+
+    for event in room.state.events.iter().chain(room.timeline.events.iter()) {
+        if event.kind == "m.room.member" {
+            if let (Some(user_id), Some(name)) =
+                (&event.state_key, event.content["displayname"].as_str())
+            {
+                names.insert(user_id.clone(), name.to_owned());
+            }
+        }
+    }
+
+    for event in &room.timeline.events {
+        if event.sender == own_id {
+            continue;
+        }
+
+        let (id, content, edit) = match event.kind.as_str() {
+            "m.room.message" => {
+                let relation = &event.content["m.relates_to"];
+                if relation["rel_type"] == "m.replace" {
+                    let id = match relation["event_id"].as_str() {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    (id, message_content(&event.content["m.new_content"]), true)
+                } else {
+                    (
+                        event.event_id.as_str(),
+                        message_content(&event.content),
+                        false,
+                    )
+                }
+            }
+            "m.sticker" => (
+                event.event_id.as_str(),
+                event.content["url"].as_str().map(|url| Content::Sticker {
+                    file_id: url.to_owned(),
+                    emoji: None,
+                }),
+                false,
+            ),
+            _ => continue,
+        };
+
+        let content = match content {
+            Some(content) => content,
+            None => continue,
+        };
+
+        let sender = match names.get(&event.sender) {
+            Some(name) => UserName(name.clone()),
+            None => UserName::from(localpart(&event.sender)),
+        };
+
+        let message = IncomingMessage {
+            id: message_id(id),
+            chat_id: chat_id(room_id),
+            date: event.origin_server_ts / 1000,
+            sender,
+            sender_id: event.sender.clone(),
+            forwarded_from: None,
+            content,
+        };
+
+        res.push(if edit {
+            Event::Edit(message)
+        } else {
+            Event::Message(message)
+        });
+    }
+
+    res
+}
+
+// state kept between sync requests
+struct SyncState {
+    transport: MatrixTransport,
+    own_id: Option<String>,
+    since: Option<String>,
+    names: HashMap<String, String>,
+}
+
+impl SyncState {
+    async fn next_events(&mut self) -> anyhow::Result<Vec<Event>> {
+        let own_id = match self.own_id {
+            Some(ref own_id) => own_id.clone(),
+            None => {
+                let whoami: WhoAmI = self.transport.get(&["account", "whoami"], &[]).await?;
+                self.own_id = Some(whoami.user_id.clone());
+                whoami.user_id
+            }
+        };
+
+        let mut query = vec![("timeout", SYNC_TIMEOUT_MS)];
+        if let Some(ref since) = self.since {
+            query.push(("since", since));
+        }
+
+        let sync: SyncResponse = self.transport.get(&["sync"], &query).await?;
+
+        let mut res = Vec::new();
+        for (room_id, room) in &sync.rooms.join {
+            self.transport
+                .rooms
+                .lock()
+                .unwrap()
+                .insert(chat_id(room_id), room_id.clone());
+
+            let events = room_events(room_id, room, &own_id, &mut self.names);
+
+            // the first sync returns old messages, they must have been handled already
+            if self.since.is_some() {
+                res.extend(events);
+            }
+        }
+
+        self.since = Some(sync.next_batch);
+        Ok(res)
+    }
+}
+
+/// Matrix client-server API adapter, rooms the bot joined are its chats
+#[derive(Clone)]
+pub(crate) struct MatrixTransport {
+    client: Client,
+    homeserver: Url,
+    access_token: String,
+    // chat ids are hashes of room ids, room ids are needed back to reply
+    rooms: Arc<Mutex<HashMap<ChatId, String>>>,
+    txn_id: Arc<AtomicU64>,
+}
+
+impl MatrixTransport {
+    pub(crate) fn new(homeserver: Url, access_token: &str) -> Self {
+        // transaction ids must not repeat after restart
+        let start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        MatrixTransport {
+            client: Client::new(),
+            homeserver,
+            access_token: access_token.to_owned(),
+            rooms: Default::default(),
+            txn_id: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .expect("homeserver url must be a base")
+            .pop_if_empty()
+            .extend(&["_matrix", "client", "v3"])
+            .extend(path);
+        url
+    }
+
+    async fn get<T>(&self, path: &[&str], query: &[(&str, &str)]) -> anyhow::Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let res = self
+            .client
+            .get(self.url(path))
+            .bearer_auth(&self.access_token)
+            .query(query)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(res.json().await?)
+    }
+
+    fn room_id(&self, chat_id: ChatId) -> anyhow::Result<String> {
+        match self.rooms.lock().unwrap().get(&chat_id) {
+            Some(room_id) => Ok(room_id.clone()),
+            None => anyhow::bail!("unknown room for chat {}", chat_id),
+        }
+    }
+
+    async fn send_event(&self, chat_id: ChatId, kind: &str, content: Value) -> anyhow::Result<()> {
+        let room_id = self.room_id(chat_id)?;
+
+        let txn_id = self.txn_id.fetch_add(1, Ordering::SeqCst).to_string();
+
+        let res = self
+            .client
+            .put(self.url(&["rooms", &room_id, "send", kind, &txn_id]))
+            .bearer_auth(&self.access_token)
+            .json(&content)
+            .send()
+            .await?;
+
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            let body: Value = res.json().await?;
+            let delay = body["retry_after_ms"].as_u64().unwrap_or(1000);
+            return Err(RetryAfter(Duration::from_millis(delay)).into());
+        }
+
+        res.error_for_status()?;
+        Ok(())
+    }
+}
+
+impl Transport for MatrixTransport {
+    fn updates(&self) -> LocalBoxStream<'static, anyhow::Result<Event>> {
+        let state = SyncState {
+            transport: self.clone(),
+            own_id: None,
+            since: None,
+            names: HashMap::new(),
+        };
+
+        stream::unfold(state, |mut state| async move {
+            let events = match state.next_events().await {
+                Ok(events) => events.into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            };
+            Some((stream::iter(events), state))
+        })
+        .flatten()
+        .boxed_local()
+    }
+
+    async fn reply_text(&self, to: &IncomingMessage, text: &str) -> anyhow::Result<()> {
+        let content = json!({
+            "msgtype": "m.text",
+            "body": text,
+        });
+        self.send_event(to.chat_id, "m.room.message", content).await
+    }
+
+    async fn reply_sticker(&self, to: &IncomingMessage, file_id: &str) -> anyhow::Result<()> {
+        let content = json!({
+            "body": "sticker",
+            "url": file_id,
+            "info": {},
+        });
+        self.send_event(to.chat_id, "m.sticker", content).await
+    }
+
+    // there are no buttons in Matrix, so choices are listed in the message
+    async fn reply_choices(
+        &self,
+        to: &IncomingMessage,
+        text: &str,
+        choices: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        let mut body = text.to_owned();
+        for (label, _) in choices {
+            body.push('\n');
+            body.push_str(label);
+        }
+        self.reply_text(to, &body).await
+    }
+
+    async fn answer_vote(&self, _vote: &Vote, _text: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn is_admin(&self, message: &IncomingMessage) -> anyhow::Result<bool> {
+        let room_id = self.room_id(message.chat_id)?;
+        let levels: PowerLevels = self
+            .get(&["rooms", &room_id, "state", "m.room.power_levels"], &[])
+            .await?;
+
+        let level = levels
+            .users
+            .get(&message.sender_id)
+            .copied()
+            .unwrap_or(levels.users_default);
+        Ok(level >= levels.state_default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server};
+
+    use super::*;
+
+    const ROOM: &str = "!room:localhost";
+
+    const INITIAL_SYNC: &str = r#"{
+        "next_batch": "s1",
+        "rooms": {"join": {"!room:localhost": {
+            "state": {"events": [
+                {"type": "m.room.member", "event_id": "$m1", "sender": "@alice:localhost",
+                 "state_key": "@alice:localhost", "content": {"membership": "join", "displayname": "Alice"}}
+            ]},
+            "timeline": {"events": [
+                {"type": "m.room.message", "event_id": "$old", "sender": "@alice:localhost",
+                 "origin_server_ts": 1600000000000, "content": {"msgtype": "m.text", "body": "old news"}}
+            ]}
+        }}}
+    }"#;
+
+    const SYNC: &str = r#"{
+        "next_batch": "s2",
+        "rooms": {"join": {"!room:localhost": {
+            "timeline": {"events": [
+                {"type": "m.room.message", "event_id": "$e1", "sender": "@alice:localhost",
+                 "origin_server_ts": 1600000001000, "content": {"msgtype": "m.text", "body": "hello there"}},
+                {"type": "m.room.message", "event_id": "$e2", "sender": "@mimic:localhost",
+                 "origin_server_ts": 1600000002000, "content": {"msgtype": "m.text", "body": "my own words"}},
+                {"type": "m.room.message", "event_id": "$e3", "sender": "@bob:localhost",
+                 "origin_server_ts": 1600000003000, "content": {"msgtype": "m.text",
+                 "body": "> <@alice:localhost> hello there\n\nhi Alice",
+                 "m.relates_to": {"m.in_reply_to": {"event_id": "$e1"}}}},
+                {"type": "m.room.message", "event_id": "$e4", "sender": "@alice:localhost",
+                 "origin_server_ts": 1600000004000, "content": {"msgtype": "m.text", "body": "* hello where",
+                 "m.new_content": {"msgtype": "m.text", "body": "hello where"},
+                 "m.relates_to": {"rel_type": "m.replace", "event_id": "$e1"}}},
+                {"type": "m.sticker", "event_id": "$e5", "sender": "@bob:localhost",
+                 "origin_server_ts": 1600000005000, "content": {"body": "cat", "url": "mxc://localhost/cat"}},
+                {"type": "m.room.message", "event_id": "$e6", "sender": "@bob:localhost",
+                 "origin_server_ts": 1600000006000, "content": {"msgtype": "m.image",
+                 "body": "look at this", "filename": "cat.png", "url": "mxc://localhost/cat.png"}},
+                {"type": "m.room.message", "event_id": "$e7", "sender": "@bob:localhost",
+                 "origin_server_ts": 1600000007000, "content": {"msgtype": "m.image",
+                 "body": "dog.png", "url": "mxc://localhost/dog.png"}}
+            ]}
+        }}}
+    }"#;
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    // pretends to be a homeserver, records requests which send events
+    fn serve_homeserver() -> (SocketAddr, Requests) {
+        let sent: Requests = Default::default();
+        let recorded = sent.clone();
+
+        let make_svc = make_service_fn(move |_| {
+            let sent = sent.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let sent = sent.clone();
+                    async move {
+                        let path = req.uri().path().to_owned();
+                        let query = req.uri().query().unwrap_or_default().to_owned();
+
+                        let body = if req.method() == Method::PUT {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            sent.lock()
+                                .unwrap()
+                                .push((path, String::from_utf8(body.to_vec()).unwrap()));
+                            r#"{"event_id": "$sent"}"#
+                        } else if path.ends_with("/state/m.room.power_levels") {
+                            r#"{"users": {"@alice:localhost": 100}, "users_default": 0}"#
+                        } else if path.ends_with("/account/whoami") {
+                            r#"{"user_id": "@mimic:localhost"}"#
+                        } else if query.contains("since=s1") {
+                            SYNC
+                        } else if query.contains("since=") {
+                            r#"{"next_batch": "s3"}"#
+                        } else {
+                            INITIAL_SYNC
+                        };
+
+                        Ok::<_, Infallible>(Response::new(Body::from(body)))
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, recorded)
+    }
+
+    fn transport(addr: SocketAddr) -> MatrixTransport {
+        let homeserver = format!("http://{}/", addr).parse().unwrap();
+        MatrixTransport::new(homeserver, "token")
+    }
+
+    #[test]
+    fn numeric_ids_are_stable() {
+        assert_eq!(numeric_id(""), 0xcbf2_9ce4_8422_2325_u64 as i64);
+        assert_eq!(numeric_id("a"), 0xaf63_dc4c_8601_ec8c_u64 as i64);
+        assert_ne!(chat_id("!a:localhost"), chat_id("!b:localhost"));
+    }
+
+    #[test]
+    fn room_events_are_converted() {
+        let sync: SyncResponse = serde_json::from_str(SYNC).unwrap();
+        let mut names = HashMap::new();
+        names.insert("@alice:localhost".to_owned(), "Alice".to_owned());
+
+        let events = room_events(ROOM, &sync.rooms.join[ROOM], "@mimic:localhost", &mut names);
+
+        let summary = events
+            .iter()
+            .map(|event| match event {
+                Event::Message(msg) => {
+                    ("message", msg.id, msg.sender.0.clone(), msg.content.clone())
+                }
+                Event::Edit(msg) => ("edit", msg.id, msg.sender.0.clone(), msg.content.clone()),
+                Event::Vote(vote) => panic!("unexpected vote: {:?}", vote),
+            })
+            .map(|(kind, id, sender, content)| {
+                let content = match content {
+                    Content::Text(text) => format!("text {}", text),
+                    Content::Caption(text) => format!("caption {}", text),
+                    Content::Sticker { file_id, .. } => format!("sticker {}", file_id),
+                };
+                (kind, id, sender, content)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "message",
+                    message_id("$e1"),
+                    "Alice".to_owned(),
+                    "text hello there".to_owned()
+                ),
+                (
+                    "message",
+                    message_id("$e3"),
+                    "bob".to_owned(),
+                    "text hi Alice".to_owned()
+                ),
+                (
+                    "edit",
+                    message_id("$e1"),
+                    "Alice".to_owned(),
+                    "text hello where".to_owned()
+                ),
+                (
+                    "message",
+                    message_id("$e5"),
+                    "bob".to_owned(),
+                    "sticker mxc://localhost/cat".to_owned()
+                ),
+                (
+                    "message",
+                    message_id("$e6"),
+                    "bob".to_owned(),
+                    "caption look at this".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn new_messages_are_received_and_replied_to() {
+        let (addr, sent) = serve_homeserver();
+        let transport = transport(addr);
+
+        let events = transport
+            .updates()
+            .take(2)
+            .collect::<Vec<anyhow::Result<Event>>>()
+            .await;
+
+        let message = match events[0] {
+            Ok(Event::Message(ref message)) => message.clone(),
+            ref other => panic!("unexpected event: {:?}", other),
+        };
+
+        // history returned by the first sync is skipped
+        assert_eq!(message.id, message_id("$e1"));
+        assert_eq!(message.chat_id, chat_id(ROOM));
+        assert_eq!(message.sender.0, "Alice");
+        assert_eq!(message.date, 1_600_000_001);
+
+        transport.reply_text(&message, "Alice: hi").await.unwrap();
+
+        assert!(transport.is_admin(&message).await.unwrap());
+        match events[1] {
+            Ok(Event::Message(ref message)) => assert!(!transport.is_admin(message).await.unwrap()),
+            ref other => panic!("unexpected event: {:?}", other),
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]
+            .0
+            .starts_with("/_matrix/client/v3/rooms/!room:localhost/send/m.room.message/"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&sent[0].1).unwrap(),
+            json!({"msgtype": "m.text", "body": "Alice: hi"})
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::stream::{LocalBoxStream, StreamExt, TryStreamExt};
+use telegram_bot::{
+    Api, CallbackQuery, CanGetChatMemberForChat, ChatMemberStatus, ForwardFrom,
+    InlineKeyboardButton, InlineKeyboardMarkup, Message, MessageKind, SendMessage, Update,
+    UpdateKind, UserId,
+};
+use tokio::sync::mpsc;
+
+use super::{Content, Event, IncomingMessage, RetryAfter, Transport, Vote};
+use crate::brain::UserName;
+use crate::requests::{AnswerCallbackQuery, SendSticker};
+
+fn full_name(first_name: &str, last_name: Option<String>) -> String {
+    match last_name {
+        Some(last_name) => format!("{} {}", first_name, last_name),
+        None => first_name.to_owned(),
+    }
+}
+
+fn forward_author(from: &ForwardFrom) -> UserName {
+    match from {
+        ForwardFrom::User { ref user } => {
+            UserName(full_name(&user.first_name, user.last_name.clone()))
+        }
+        ForwardFrom::ChannelHiddenUser { ref sender_name } => UserName(sender_name.clone()),
+        ForwardFrom::Channel { ref channel, .. } => UserName(channel.title.clone()),
+    }
+}
+
+fn content(kind: MessageKind) -> Option<Content> {
+    match kind {
+        MessageKind::Text { data, .. } => Some(Content::Text(data)),
+        MessageKind::Sticker { data } => Some(Content::Sticker {
+            file_id: data.file_id,
+            emoji: data.emoji,
+        }),
+        MessageKind::Photo { caption, .. }
+        | MessageKind::Video { caption, .. }
+        | MessageKind::Document { caption, .. } => caption.map(Content::Caption),
+        _ => None,
+    }
+}
+
+/// Converts telegram message, returns None for messages the bot can't learn from
+fn incoming_message(message: Message) -> Option<IncomingMessage> {
+    Some(IncomingMessage {
+        id: message.id,
+        chat_id: message.chat.id(),
+        date: message.date,
+        sender: UserName(full_name(
+            &message.from.first_name,
+            message.from.last_name.clone(),
+        )),
+        sender_id: message.from.id.to_string(),
+        forwarded_from: message.forward.as_ref().map(|f| forward_author(&f.from)),
+        content: content(message.kind)?,
+    })
+}
+
+/// Converts button press, presses under messages too old to be known are ignored
+fn vote(query: CallbackQuery) -> Option<Vote> {
+    // telegram-bot keeps query id private, though it's a plain string in the API
+    let id = serde_json::to_value(&query.id).ok()?.as_str()?.to_owned();
+
+    Some(Vote {
+        id,
+        chat_id: query.message?.chat.id(),
+        voter: UserName(full_name(&query.from.first_name, query.from.last_name)),
+        data: query.data?,
+    })
+}
+
+fn event(update: Update) -> Option<Event> {
+    match update.kind {
+        UpdateKind::Message(message) => incoming_message(message).map(Event::Message),
+        UpdateKind::EditedMessage(message) => incoming_message(message).map(Event::Edit),
+        UpdateKind::CallbackQuery(query) => vote(query).map(Event::Vote),
+        _ => None,
+    }
+}
+
+// telegram-bot keeps error details private, flood control delay is only seen in the message
+fn retry_after(msg: &str) -> Option<Duration> {
+    let (_, secs) = msg.rsplit_once("retry after: ")?;
+    secs.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn send_error(err: telegram_bot::Error) -> anyhow::Error {
+    match retry_after(&err.to_string()) {
+        Some(delay) => RetryAfter(delay).into(),
+        None => err.into(),
+    }
+}
+
+pub(crate) struct TelegramTransport {
+    api: Api,
+    // updates received by the webhook listener, updates are long polled if there is none
+    webhook: Mutex<Option<mpsc::Receiver<Update>>>,
+}
+
+impl TelegramTransport {
+    pub(crate) fn new(api: Api, webhook: Option<mpsc::Receiver<Update>>) -> Self {
+        TelegramTransport {
+            api,
+            webhook: Mutex::new(webhook),
+        }
+    }
+}
+
+impl Transport for TelegramTransport {
+    fn updates(&self) -> LocalBoxStream<'static, anyhow::Result<Event>> {
+        // the listener has a single queue, so its updates can be taken only once
+        let updates = match self.webhook.lock().unwrap().take() {
+            Some(webhook) => webhook.map(Ok).boxed_local(),
+            // Fetch new updates via long poll method
+            None => self.api.stream().map_err(anyhow::Error::from).boxed_local(),
+        };
+
+        updates
+            .filter_map(|update| async move { update.map(event).transpose() })
+            .boxed_local()
+    }
+
+    async fn reply_text(&self, to: &IncomingMessage, text: &str) -> anyhow::Result<()> {
+        self.api
+            .send(SendMessage::new(to.chat_id, text).reply_to(to.id))
+            .await
+            .map_err(send_error)?;
+        Ok(())
+    }
+
+    async fn reply_sticker(&self, to: &IncomingMessage, file_id: &str) -> anyhow::Result<()> {
+        self.api
+            .send(SendSticker::new(to.chat_id, file_id).reply_to(to.id))
+            .await
+            .map_err(send_error)?;
+        Ok(())
+    }
+
+    async fn reply_choices(
+        &self,
+        to: &IncomingMessage,
+        text: &str,
+        choices: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        let keyboard = choices
+            .iter()
+            .map(|(label, data)| vec![InlineKeyboardButton::callback(label, data)])
+            .collect::<Vec<Vec<InlineKeyboardButton>>>();
+
+        self.api
+            .send(
+                SendMessage::new(to.chat_id, text)
+                    .reply_to(to.id)
+                    .reply_markup(InlineKeyboardMarkup::from(keyboard)),
+            )
+            .await
+            .map_err(send_error)?;
+        Ok(())
+    }
+
+    async fn answer_vote(&self, vote: &Vote, text: &str) -> anyhow::Result<()> {
+        self.api
+            .send(AnswerCallbackQuery::new(vote.id.as_str(), text))
+            .await
+            .map_err(send_error)?;
+        Ok(())
+    }
+
+    async fn is_admin(&self, message: &IncomingMessage) -> anyhow::Result<bool> {
+        // in a private chat the only member besides the bot is in charge
+        if i64::from(message.chat_id) > 0 {
+            return Ok(true);
+        }
+
+        let user_id = UserId::new(message.sender_id.parse()?);
+        let member = self
+            .api
+            .send(message.chat_id.get_member(user_id))
+            .await
+            .map_err(send_error)?;
+
+        Ok(matches!(
+            member.status,
+            ChatMemberStatus::Creator | ChatMemberStatus::Administrator
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use telegram_bot::{ChatId, HttpResponse, JsonTrueToUnitResponse, MessageId, ResponseType};
+
+    use super::*;
+
+    fn parse(raw: &str) -> Option<Event> {
+        event(serde_json::from_str::<Update>(raw).expect("update must be valid"))
+    }
+
+    #[test]
+    fn text_message() {
+        let event = parse(
+            r#"{"update_id": 1, "message": {
+                "message_id": 5, "date": 1600000000, "text": "hello there",
+                "from": {"id": 1, "is_bot": false, "first_name": "Alice", "last_name": "Smith"},
+                "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false}
+            }}"#,
+        );
+
+        let message = match event {
+            Some(Event::Message(message)) => message,
+            other => panic!("unexpected event: {:?}", other),
+        };
+
+        assert_eq!(message.id, MessageId::new(5));
+        assert_eq!(message.chat_id, ChatId::new(-100));
+        assert_eq!(message.date, 1_600_000_000);
+        assert_eq!(message.sender.0, "Alice Smith");
+        assert_eq!(message.sender_id, "1");
+        assert!(message.forwarded_from.is_none());
+        assert_eq!(message.text(), Some("hello there"));
+    }
+
+    #[test]
+    fn button_press() {
+        let event = parse(
+            r#"{"update_id": 3, "callback_query": {
+                "id": "4382", "chat_instance": "-7", "data": "quiz:5:2",
+                "from": {"id": 2, "is_bot": false, "first_name": "Bob"},
+                "message": {
+                    "message_id": 7, "date": 1600000000, "text": "Who said it?",
+                    "from": {"id": 3, "is_bot": true, "first_name": "mimic"},
+                    "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false}
+                }
+            }}"#,
+        );
+
+        let vote = match event {
+            Some(Event::Vote(vote)) => vote,
+            other => panic!("unexpected event: {:?}", other),
+        };
+
+        assert_eq!(vote.id, "4382");
+        assert_eq!(vote.chat_id, ChatId::new(-100));
+        assert_eq!(vote.voter.0, "Bob");
+        assert_eq!(vote.data, "quiz:5:2");
+    }
+
+    #[test]
+    fn forwarded_message_keeps_author() {
+        let event = parse(
+            r#"{"update_id": 2, "message": {
+                "message_id": 6, "date": 1600000000, "text": "wise words",
+                "from": {"id": 1, "is_bot": false, "first_name": "Alice"},
+                "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false},
+                "forward_from": {"id": 2, "is_bot": false, "first_name": "Bob"},
+                "forward_date": 1500000000
+            }}"#,
+        );
+
+        match event {
+            Some(Event::Message(message)) => {
+                assert_eq!(message.sender.0, "Alice");
+                assert_eq!(
+                    message.forwarded_from.map(|name| name.0),
+                    Some("Bob".to_owned())
+                );
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn edited_caption() {
+        let event = parse(
+            r#"{"update_id": 3, "edited_message": {
+                "message_id": 7, "date": 1600000000, "edit_date": 1600000010,
+                "from": {"id": 1, "is_bot": false, "first_name": "Alice"},
+                "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false},
+                "photo": [{"file_id": "photo", "width": 10, "height": 10}],
+                "caption": "look at this"
+            }}"#,
+        );
+
+        match event {
+            Some(Event::Edit(message)) => assert_eq!(message.text(), Some("look at this")),
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn flood_control_delay_is_recognized() {
+        let response = HttpResponse {
+            body: Some(
+                br#"{"ok": false, "error_code": 429, "description": "Too Many Requests: retry after 7",
+                    "parameters": {"retry_after": 7}}"#
+                    .to_vec(),
+            ),
+        };
+        let err = <JsonTrueToUnitResponse as ResponseType>::deserialize(response).unwrap_err();
+
+        assert_eq!(retry_after(&err.to_string()), Some(Duration::from_secs(7)));
+        assert_eq!(retry_after("Bad Request: message to reply not found"), None);
+    }
+
+    #[test]
+    fn unsupported_message_is_skipped() {
+        let event = parse(
+            r#"{"update_id": 4, "message": {
+                "message_id": 8, "date": 1600000000,
+                "from": {"id": 1, "is_bot": false, "first_name": "Alice"},
+                "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false},
+                "location": {"longitude": 30.3, "latitude": 59.9}
+            }}"#,
+        );
+
+        assert!(event.is_none());
+    }
+}
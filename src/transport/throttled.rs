@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::LocalBoxStream;
+use telegram_bot::ChatId;
+
+use super::{Event, IncomingMessage, RetryAfter, Transport, Vote};
+use crate::errors::{self, Severity};
+use crate::limits::Window;
+use crate::CONFIG;
+
+// delay before the first retry of a message failed because of network or platform issues
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(500);
+// how many messages may wait for their turn in a chat, newer ones are dropped
+const MAX_QUEUED: usize = 20;
+
+enum Outgoing {
+    Text(String),
+    Sticker(String),
+    Choices(String, Vec<(String, String)>),
+}
+
+struct Queued {
+    to: IncomingMessage,
+    outgoing: Outgoing,
+    attempt: usize,
+}
+
+struct ChatQueue {
+    window: Window,
+    messages: VecDeque<Queued>,
+    // nothing is sent to the chat until then after flood control or network errors
+    resume_at: Option<Instant>,
+}
+
+impl ChatQueue {
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.messages.is_empty()
+            && self.resume_at.iter().all(|resume_at| *resume_at <= now)
+            && self.window.is_idle(now)
+    }
+}
+
+struct Limits {
+    global: Window,
+    chats: HashMap<ChatId, ChatQueue>,
+}
+
+/// Queues outgoing messages per chat so they don't exceed platform rate limits,
+/// messages which have to wait are sent later by `send_queued` and never hold up
+/// other chats, requests rejected by flood control are repeated after the requested
+/// delay and ones failed because of network issues are repeated with backoff
+pub(crate) struct Throttled<T> {
+    inner: T,
+    chat_rate: usize,
+    limits: Mutex<Limits>,
+}
+
+impl<T: Transport> Throttled<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        let config = CONFIG.get();
+        Self::with_rates(inner, config.global_send_rate, config.chat_send_rate)
+    }
+
+    /// Limits messages to `global_rate` per second and `chat_rate` per minute in a chat
+    pub(crate) fn with_rates(inner: T, global_rate: usize, chat_rate: usize) -> Self {
+        Throttled {
+            inner,
+            chat_rate,
+            limits: Mutex::new(Limits {
+                global: Window::new(global_rate, Duration::from_secs(1)),
+                chats: HashMap::new(),
+            }),
+        }
+    }
+
+    fn enqueue(&self, to: &IncomingMessage, outgoing: Outgoing) {
+        let mut limits = self.limits.lock().unwrap();
+        let chat_rate = self.chat_rate;
+        let chat = limits.chats.entry(to.chat_id).or_insert_with(|| ChatQueue {
+            window: Window::new(chat_rate, Duration::from_secs(60)),
+            messages: VecDeque::new(),
+            resume_at: None,
+        });
+
+        if chat.messages.len() >= MAX_QUEUED {
+            log::warn!("send queue of chat {} is full, message dropped", to.chat_id);
+            return;
+        }
+
+        chat.messages.push_back(Queued {
+            to: to.clone(),
+            outgoing,
+            attempt: 0,
+        });
+    }
+
+    // takes the next message of the chat if it fits into both global and chat limits
+    fn next_ready(&self, chat_id: ChatId, now: Instant) -> Option<Queued> {
+        let mut limits = self.limits.lock().unwrap();
+        let Limits {
+            ref mut global,
+            ref mut chats,
+        } = *limits;
+        let chat = chats.get_mut(&chat_id)?;
+
+        if chat.messages.is_empty() || chat.resume_at.iter().any(|resume_at| *resume_at > now) {
+            return None;
+        }
+
+        if let Some(wait) = global.wait_time(now).max(chat.window.wait_time(now)) {
+            log::debug!(
+                "send rate limit reached for chat {}, next message in {:?}",
+                chat_id,
+                wait
+            );
+            return None;
+        }
+
+        global.record(now);
+        chat.window.record(now);
+        chat.messages.pop_front()
+    }
+
+    async fn send(&self, queued: &Queued) -> anyhow::Result<()> {
+        match queued.outgoing {
+            Outgoing::Text(ref text) => self.inner.reply_text(&queued.to, text).await,
+            Outgoing::Sticker(ref file_id) => self.inner.reply_sticker(&queued.to, file_id).await,
+            Outgoing::Choices(ref text, ref choices) => {
+                self.inner.reply_choices(&queued.to, text, choices).await
+            }
+        }
+    }
+
+    // sends queued messages of the chat while the limits allow, a message failed because of
+    // flood control or network issues pauses the chat and is repeated later
+    async fn deliver(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        while let Some(mut queued) = self.next_ready(chat_id, Instant::now()) {
+            let err = match self.send(&queued).await {
+                Ok(()) => continue,
+                Err(err) => err,
+            };
+
+            if queued.attempt >= CONFIG.get().send_retry_attempts {
+                return Err(err);
+            }
+
+            let delay = match err.downcast_ref::<RetryAfter>() {
+                Some(RetryAfter(delay)) => *delay,
+                None if errors::classify(&err) == Severity::Transient => {
+                    SEND_RETRY_DELAY * 2u32.pow(queued.attempt as u32)
+                }
+                None => return Err(err),
+            };
+
+            log::warn!(
+                "error sending to chat {}: {}, retry in {:?}",
+                chat_id,
+                err,
+                delay
+            );
+            queued.attempt += 1;
+
+            let mut limits = self.limits.lock().unwrap();
+            if let Some(chat) = limits.chats.get_mut(&chat_id) {
+                chat.resume_at = Some(Instant::now() + delay);
+                chat.messages.push_front(queued);
+            }
+            break;
+        }
+
+        Ok(())
+    }
+
+    async fn push(&self, to: &IncomingMessage, outgoing: Outgoing) -> anyhow::Result<()> {
+        self.enqueue(to, outgoing);
+        self.deliver(to.chat_id).await
+    }
+}
+
+impl<T: Transport> Transport for Throttled<T> {
+    fn updates(&self) -> LocalBoxStream<'static, anyhow::Result<Event>> {
+        self.inner.updates()
+    }
+
+    async fn reply_text(&self, to: &IncomingMessage, text: &str) -> anyhow::Result<()> {
+        self.push(to, Outgoing::Text(text.to_owned())).await
+    }
+
+    async fn reply_sticker(&self, to: &IncomingMessage, file_id: &str) -> anyhow::Result<()> {
+        self.push(to, Outgoing::Sticker(file_id.to_owned())).await
+    }
+
+    async fn reply_choices(
+        &self,
+        to: &IncomingMessage,
+        text: &str,
+        choices: &[(String, String)],
+    ) -> anyhow::Result<()> {
+        self.push(to, Outgoing::Choices(text.to_owned(), choices.to_vec()))
+            .await
+    }
+
+    // answers are shown to the voter only and aren't limited like chat messages
+    async fn answer_vote(&self, vote: &Vote, text: &str) -> anyhow::Result<()> {
+        self.inner.answer_vote(vote, text).await
+    }
+
+    // checks aren't messages, so they are sent right away
+    async fn is_admin(&self, message: &IncomingMessage) -> anyhow::Result<bool> {
+        self.inner.is_admin(message).await
+    }
+
+    async fn send_queued(&self) -> anyhow::Result<()> {
+        let chat_ids = {
+            let mut limits = self.limits.lock().unwrap();
+            let now = Instant::now();
+
+            limits.chats.retain(|_, chat| !chat.is_idle(now));
+            limits
+                .chats
+                .iter()
+                .filter(|(_, chat)| !chat.messages.is_empty())
+                .map(|(chat_id, _)| *chat_id)
+                .collect::<Vec<ChatId>>()
+        };
+
+        for chat_id in chat_ids {
+            self.deliver(chat_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::fake::{self, FakeTransport, Sent};
+    use crate::transport::Content;
+
+    fn text(chat_id: i64, id: i64) -> IncomingMessage {
+        fake::message(chat_id, id, "Alice", Content::Text("hi".to_owned()))
+    }
+
+    fn sent_to(transport: &Throttled<FakeTransport>) -> Vec<(i64, String)> {
+        transport
+            .inner
+            .take_sent()
+            .into_iter()
+            .filter_map(|sent| match sent {
+                Sent::Text { chat_id, text, .. } => Some((i64::from(chat_id), text)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn throttled_chat_doesnt_hold_up_others() {
+        let transport = Throttled::with_rates(FakeTransport::new(), 0, 1);
+
+        transport.reply_text(&text(1, 1), "first").await.unwrap();
+        transport.reply_text(&text(1, 2), "second").await.unwrap();
+        transport.reply_text(&text(2, 3), "other").await.unwrap();
+        transport.send_queued().await.unwrap();
+
+        assert_eq!(
+            sent_to(&transport),
+            vec![(1, "first".to_owned()), (2, "other".to_owned())]
+        );
+        let limits = transport.limits.lock().unwrap();
+        assert_eq!(limits.chats[&ChatId::new(1)].messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn flood_control_pauses_only_its_chat() {
+        let transport = Throttled::with_rates(FakeTransport::new(), 0, 0);
+        transport.inner.flood_chat(1, Duration::from_millis(50));
+
+        transport.reply_text(&text(1, 1), "first").await.unwrap();
+        transport.reply_text(&text(2, 2), "other").await.unwrap();
+        transport.reply_text(&text(1, 3), "second").await.unwrap();
+        assert_eq!(sent_to(&transport), vec![(2, "other".to_owned())]);
+
+        tokio::time::delay_for(Duration::from_millis(60)).await;
+        transport.send_queued().await.unwrap();
+
+        assert_eq!(
+            sent_to(&transport),
+            vec![(1, "first".to_owned()), (1, "second".to_owned())]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use telegram_bot::Update;
+use tokio::sync::mpsc;
+
+use super::CONFIG;
+
+// telegram puts secret token given to setWebhook into this header
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+// how many received updates may wait for the handler
+const UPDATES_QUEUE_SIZE: usize = 100;
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// Compares in time not depending on where the values differ, so the secret can't be guessed
+// byte by byte from how fast requests are rejected
+fn same_secret(token: &[u8], secret: &[u8]) -> bool {
+    token.len() == secret.len()
+        && token
+            .iter()
+            .zip(secret)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Queues update posted to `path`, requests without `secret` in the header are rejected if it's set
+async fn handle(
+    req: Request<Body>,
+    mut tx: mpsc::Sender<Update>,
+    path: &str,
+    secret: Option<&str>,
+) -> Response<Body> {
+    if req.method() != Method::POST || req.uri().path() != path {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    if let Some(secret) = secret {
+        let token = req
+            .headers()
+            .get(SECRET_HEADER)
+            .map_or(&[][..], |v| v.as_bytes());
+        if !same_secret(token, secret.as_bytes()) {
+            log::warn!("webhook request with wrong secret token rejected");
+            return status(StatusCode::FORBIDDEN);
+        }
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("error reading webhook request: {}", err);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let value = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(value) => value,
+        Err(err) => {
+            log::error!("webhook request isn't json: {}", err);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    match serde_json::from_value::<Update>(value) {
+        Ok(update) => {
+            if tx.send(update).await.is_err() {
+                // updates are not handled anymore, let telegram deliver it later
+                return status(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        }
+        // kinds of updates the bot doesn't know would be resent over and over, so just skip them
+        Err(err) => log::error!("unable to parse update: {}", err),
+    }
+
+    status(StatusCode::OK)
+}
+
+/// Starts HTTP server receiving updates telegram posts to the webhook,
+/// returns the receiving end of the updates queue
+pub(crate) fn listen(addr: SocketAddr) -> anyhow::Result<mpsc::Receiver<Update>> {
+    let (tx, rx) = mpsc::channel(UPDATES_QUEUE_SIZE);
+    // telegram is told the path and secret on start, so they aren't reloaded
+    let config = CONFIG.get();
+
+    let make_svc = make_service_fn(move |_| {
+        let tx = tx.clone();
+        let config = config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let tx = tx.clone();
+                let config = config.clone();
+                async move {
+                    let res = handle(
+                        req,
+                        tx,
+                        &config.webhook_path,
+                        config.webhook_secret.as_deref(),
+                    )
+                    .await;
+                    Ok::<_, Infallible>(res)
+                }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .map_err(|err| anyhow::anyhow!("unable to listen for webhook on {}: {}", addr, err))?
+        .serve(make_svc);
+    log::info!(
+        "listening for webhook updates on {}{}",
+        addr,
+        CONFIG.get().webhook_path
+    );
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            log::error!("webhook server error: {}", err);
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPDATE: &str = r#"{"update_id": 1, "message": {
+        "message_id": 5, "date": 1600000000, "text": "hello there",
+        "from": {"id": 1, "is_bot": false, "first_name": "Alice"},
+        "chat": {"id": -100, "type": "group", "title": "friends", "all_members_are_administrators": false}
+    }}"#;
+
+    fn request(path: &str, secret: Option<&str>, body: &str) -> Request<Body> {
+        let mut req = Request::post(path);
+        if let Some(secret) = secret {
+            req = req.header(SECRET_HEADER, secret);
+        }
+        req.body(Body::from(body.to_owned())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_checked() {
+        let (tx, mut rx) = mpsc::channel(UPDATES_QUEUE_SIZE);
+
+        let cases = [
+            ("/hook", Some("s3cret"), UPDATE, StatusCode::OK),
+            ("/hook", Some("guess"), UPDATE, StatusCode::FORBIDDEN),
+            ("/hook", Some("s3crex"), UPDATE, StatusCode::FORBIDDEN),
+            ("/hook", Some("s3cre"), UPDATE, StatusCode::FORBIDDEN),
+            ("/hook", None, UPDATE, StatusCode::FORBIDDEN),
+            ("/", Some("s3cret"), UPDATE, StatusCode::NOT_FOUND),
+            (
+                "/hook",
+                Some("s3cret"),
+                "{not json",
+                StatusCode::BAD_REQUEST,
+            ),
+            // valid json the bot can't parse is skipped, telegram would repeat it otherwise
+            (
+                "/hook",
+                Some("s3cret"),
+                r#"{"update_id": 2}"#,
+                StatusCode::OK,
+            ),
+        ];
+
+        for &(path, secret, body, code) in cases.iter() {
+            let res = handle(
+                request(path, secret, body),
+                tx.clone(),
+                "/hook",
+                Some("s3cret"),
+            )
+            .await;
+            assert_eq!(res.status(), code, "{} {:?} {}", path, secret, body);
+        }
+
+        let wrong_method = Request::get("/hook")
+            .header(SECRET_HEADER, "s3cret")
+            .body(Body::empty())
+            .unwrap();
+        let res = handle(wrong_method, tx.clone(), "/hook", Some("s3cret")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        drop(tx);
+        let received = rx.recv().await.expect("update must be queued");
+        assert_eq!(received.id, 1);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn busy_port_is_reported() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert!(listen(addr).is_err());
+    }
+}